@@ -1 +1 @@
-cargo build --example=hi_rust_rocks --release
\ No newline at end of file
+cargo build --example=hi_rust_rocks --features serde --release
\ No newline at end of file
@@ -1,7 +1,11 @@
-use std::{io, fmt::Write};
+use std::{borrow::Cow, io, io::BufRead, ops::Bound, time::Duration};
+use std::path::{Component, Path, PathBuf};
 
-use may_minihttp::{HttpService, HttpServiceFactory, Request, Response, KvUtil, MockKvUtil};
-use rocksdb::DB;
+use may_minihttp::{
+    BodyWriter, HttpService, HttpServiceFactory, Request, Response, KvUtil, LevelDbKvUtil,
+    BackupPolicy, Backups, BatchOp, Durability, EngineConfig, EngineStats, Expected,
+    ExportEntry, NamespaceOpts, RocksKvUtil, ScanOpts, StorageError, ZRange,
+};
 use serde::{Deserialize, Serialize};
 
 extern crate serde;
@@ -11,8 +15,37 @@ extern crate serde;
 //     message: &'static str,
 // }
 
-struct Techempower {
-    kv: MockKvUtil
+struct Techempower<K> {
+    kv: K,
+    // set when the server was started with a backup directory
+    backups: Option<Backups>,
+    // the directory the admin routes read and write files in, see `admin_path`
+    files: PathBuf
+}
+
+// what the routes need from an engine beyond `KvUtil`,
+// the RocksDB only features are answered with 501 on the others
+trait Engine: KvUtil + Clone + Send + 'static {
+    fn rocks(&self) -> Option<&RocksKvUtil> {
+        None
+    }
+
+    // `None` for an unknown namespace
+    fn namespace(&self, _name: &str) -> Option<Self> {
+        None
+    }
+}
+
+impl Engine for LevelDbKvUtil {}
+
+impl Engine for RocksKvUtil {
+    fn rocks(&self) -> Option<&RocksKvUtil> {
+        Some(self)
+    }
+
+    fn namespace(&self, name: &str) -> Option<Self> {
+        RocksKvUtil::namespace(self, name)
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -21,104 +54,1236 @@ struct KeyValue<'a> {
     value: &'a str
 }
 
+// one `/batch` write, a plain `{"key", "value"}` is a set
+//
+// `op` is one of "set", "del", "zadd" and "zrmv", the sorted set ops take
+// the member as `value`, `ttl_ms` falls back to the query parameter
+#[derive(Deserialize, Debug)]
+struct BatchEntry<'a> {
+    op: Option<&'a str>,
+    key: &'a str,
+    value: Option<&'a str>,
+    score: Option<u32>,
+    ttl_ms: Option<u64>
+}
+
+impl<'a> BatchEntry<'a> {
+    // `None` for an unknown op or a missing field
+    fn to_op(&self, default_ttl: Option<Duration>) -> Option<BatchOp> {
+        let key = self.key.as_bytes().to_vec();
+        let ttl = self.ttl_ms.map(Duration::from_millis).or(default_ttl);
+        let op = match self.op.unwrap_or("set") {
+            "set" => BatchOp::Set {
+                key,
+                value: self.value?.as_bytes().to_vec(),
+                ttl,
+            },
+            "del" => BatchOp::Delete { key },
+            "zadd" => BatchOp::ZAdd {
+                key,
+                member: self.value?.as_bytes().to_vec(),
+                score: self.score?,
+                ttl,
+            },
+            "zrmv" => BatchOp::ZRmv {
+                key,
+                member: self.value?.as_bytes().to_vec(),
+            },
+            _ => return None,
+        };
+        Some(op)
+    }
+}
+
+// `/cas` request, exactly one of `version` (0 for "must not exist")
+// and `expected` (the old value) has to be given
+#[derive(Deserialize, Debug)]
+struct CasRequest<'a> {
+    key: &'a str,
+    value: &'a str,
+    version: Option<u64>,
+    expected: Option<&'a str>,
+    ttl_ms: Option<u64>
+}
+
+#[derive(Serialize, Debug)]
+struct VersionedValue<'a> {
+    value: Cow<'a, str>,
+    version: u64
+}
+
+// one `/list` entry, `value` is null for a missing key
+#[derive(Serialize, Debug)]
+struct KeyMaybeValue<'a> {
+    key: &'a str,
+    value: Option<Cow<'a, str>>
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct ZValue<'a> {
     score: u32,
     value: &'a str
 }
 
+// `/zrange/` request, missing bounds are open ended
 #[derive(Deserialize, Serialize, Debug)]
 struct ZRangeScore {
-    min_score: u32,
-    max_score: u32
+    min_score: Option<u32>,
+    max_score: Option<u32>,
+    #[serde(default)]
+    min_exclusive: bool,
+    #[serde(default)]
+    max_exclusive: bool,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    #[serde(default)]
+    reverse: bool
+}
+
+impl ZRangeScore {
+    fn to_range(&self) -> ZRange {
+        let bound = |score: Option<u32>, exclusive| match score {
+            Some(s) if exclusive => Bound::Excluded(s),
+            Some(s) => Bound::Included(s),
+            None => Bound::Unbounded,
+        };
+        ZRange {
+            min: bound(self.min_score, self.min_exclusive),
+            max: bound(self.max_score, self.max_exclusive),
+            offset: self.offset,
+            limit: self.limit,
+            reverse: self.reverse,
+        }
+    }
 }
 
+#[derive(Deserialize, Debug)]
+struct ZIncr<'a> {
+    value: &'a str,
+    delta: i64
+}
 
+// `/scan` response, `cursor` is hex and null after the last page
+#[derive(Serialize, Debug)]
+struct ScanResult<'a> {
+    entries: Vec<ScanEntry<'a>>,
+    cursor: Option<String>
+}
 
-impl HttpService for Techempower {
+// keys and values are text when they are utf-8 and hex in the `_hex`
+// field otherwise, like the export lines
+#[derive(Serialize, Debug)]
+struct ScanEntry<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_hex: Option<String>
+}
 
-    fn call(&mut self, req: Request, rsp: &mut Response) -> io::Result<()> {
-        // Bare-bones router
-        if req.path() == "/init" {
+#[derive(Serialize, Debug)]
+struct ZMember<'a> {
+    member: Cow<'a, str>,
+    score: u32
+}
+
+// the fields and values are text or hex, as in `ScanEntry`
+#[derive(Serialize, Debug)]
+struct HField<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field_hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_hex: Option<String>
+}
+
+// one `/lrange` element, text or hex as in `ScanEntry`
+#[derive(Serialize, Debug)]
+struct ListElement<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_hex: Option<String>
+}
+
+// a json line of an import file
+#[derive(Deserialize, Debug)]
+struct ImportLine {
+    key: String,
+    value: String
+}
+
+// one line of an export, the byte fields are text when they are utf-8
+// and hex in the `_hex` field otherwise
+#[derive(Serialize, Debug, Default)]
+struct ExportLine<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    member: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    member_hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field_hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>
+}
+
+// `bytes` as text, or as hex when it isn't utf-8
+fn text_or_hex(bytes: &[u8]) -> (Option<&str>, Option<String>) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (Some(text), None),
+        Err(_) => (None, Some(hex_encode(bytes))),
+    }
+}
+
+// the most bytes `/admin/export` answers in the response body,
+// which is built in memory. bigger exports go to a `?file=`
+const EXPORT_BODY_LIMIT: usize = 64 << 20;
+
+// a response body refusing to grow past `left` more bytes
+struct CappedBody<'a> {
+    body: BodyWriter<'a>,
+    left: usize,
+    exceeded: bool
+}
+
+impl<'a> io::Write for CappedBody<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.left {
+            self.exceeded = true;
+            return Err(io::Error::new(io::ErrorKind::Other, "export too large for a response"));
+        }
+        self.left -= buf.len();
+        self.body.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// write the namespace of `rocks` as json lines, answers the line count
+fn write_export(rocks: &RocksKvUtil, prefix: &[u8], out: &mut dyn io::Write) -> io::Result<u64> {
+    let mut lines = 0;
+    rocks.export(prefix, &mut |entry| {
+        let line = match &entry {
+            ExportEntry::Str { key, value, expires_at } => {
+                let (key, key_hex) = text_or_hex(key);
+                let (value, value_hex) = text_or_hex(value);
+                ExportLine {
+                    kind: "string",
+                    key,
+                    key_hex,
+                    value,
+                    value_hex,
+                    expires_at: *expires_at,
+                    ..Default::default()
+                }
+            }
+            ExportEntry::ZMember { key, member, score, expires_at } => {
+                let (key, key_hex) = text_or_hex(key);
+                let (member, member_hex) = text_or_hex(member);
+                ExportLine {
+                    kind: "zset",
+                    key,
+                    key_hex,
+                    member,
+                    member_hex,
+                    score: Some(*score),
+                    expires_at: *expires_at,
+                    ..Default::default()
+                }
+            }
+            ExportEntry::HField { key, field, value } => {
+                let (key, key_hex) = text_or_hex(key);
+                let (field, field_hex) = text_or_hex(field);
+                let (value, value_hex) = text_or_hex(value);
+                ExportLine {
+                    kind: "hash",
+                    key,
+                    key_hex,
+                    field,
+                    field_hex,
+                    value,
+                    value_hex,
+                    ..Default::default()
+                }
+            }
+            ExportEntry::ListItem { key, value } => {
+                let (key, key_hex) = text_or_hex(key);
+                let (value, value_hex) = text_or_hex(value);
+                ExportLine {
+                    kind: "list",
+                    key,
+                    key_hex,
+                    value,
+                    value_hex,
+                    ..Default::default()
+                }
+            }
+        };
+        serde_json::to_writer(&mut *out, &line).map_err(io::Error::from)?;
+        out.write_all(b"\n")?;
+        lines += 1;
+        Ok(true)
+    })?;
+    Ok(lines)
+}
+
+// one `/admin/backups` entry
+#[derive(Serialize, Debug)]
+struct BackupEntry {
+    id: u32,
+    timestamp: i64,
+    size: u64,
+    num_files: u32
+}
+
+// `/stats` response, `EngineStats` and its hit rate
+#[derive(Serialize, Debug)]
+struct StatsBody {
+    #[serde(flatten)]
+    stats: EngineStats,
+    block_cache_hit_rate: Option<f64>
+}
+
+// the json file `CONFIG` names, every field may be left out,
+// `engine` is an `EngineConfig` with the compressions in lowercase,
+// e.g. `["none", "none", "lz4", "lz4", "zstd"]`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct ServerConfig {
+    engine: EngineConfig
+}
+
+// the config file `CONFIG` names, the defaults without one
+fn server_config() -> io::Result<ServerConfig> {
+    match std::env::var("CONFIG") {
+        Ok(path) => {
+            let file = std::fs::File::open(path)?;
+            Ok(serde_json::from_reader(io::BufReader::new(file))?)
+        }
+        Err(_) => Ok(ServerConfig::default()),
+    }
+}
+
+// parse the json request body, answer 400 if it doesn't fit
+macro_rules! json_body {
+    ($req: expr, $rsp: expr) => {
+        match serde_json::from_slice($req.body_()) {
+            Ok(val) => val,
+            Err(err) => {
+                $rsp.status_code("400", "Bad Request");
+                $rsp.body_mut().extend_from_slice(err.to_string().as_bytes());
+                return Ok(());
+            }
+        }
+    };
+}
+
+// the `ttl_ms` query parameter, answer 400 if it isn't a number of millis,
+// rather than writing a key that never expires
+macro_rules! ttl_param {
+    ($query: expr, $rsp: expr) => {
+        match ttl_param($query) {
+            Ok(ttl) => ttl,
+            Err(()) => {
+                $rsp.status_code("400", "Bad Request");
+                return Ok(());
+            }
+        }
+    };
+}
+
+// decode the `%XX` escapes of a path segment, so binary keys fit in urls
+fn percent_decode(s: &str) -> Vec<u8> {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'%'
+            && i + 2 < b.len()
+            && b[i + 1].is_ascii_hexdigit()
+            && b[i + 2].is_ascii_hexdigit()
+        {
+            out.push(u8::from_str_radix(&s[i + 1..i + 3], 16).unwrap());
+            i += 3;
+        } else {
+            out.push(b[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+fn hex_encode(b: &[u8]) -> String {
+    b.iter().map(|c| format!("{:02x}", c)).collect()
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// the value of `name` in a `a=1&b=2` query string
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+// the `ttl_ms` query parameter of the write routes
+fn ttl_param(query: &str) -> Result<Option<Duration>, ()> {
+    match query_param(query, "ttl_ms") {
+        Some(ms) => ms.parse().map(|ms| Some(Duration::from_millis(ms))).map_err(|_| ()),
+        None => Ok(None),
+    }
+}
+
+// answer with a stored value, as plain text unless it is `binary`
+// or isn't utf-8
+fn value_body(rsp: &mut Response, val: Vec<u8>, binary: bool) {
+    if binary || std::str::from_utf8(&val).is_err() {
+        rsp.header("Content-Type: application/octet-stream");
+    } else {
+        rsp.header("Content-Type: text/plain");
+    }
+    rsp.body_vec(val);
+}
+
+// answer with a plain text number
+fn number_body<N: ToString>(rsp: &mut Response, n: N) {
+    rsp.header("Content-Type: text/plain");
+    rsp.body_vec(n.to_string().into_bytes());
+}
+
+// the entries of an import file, one per line: a json `{"key", "value"}`
+// or a bare key, as `scripts/set.lua` writes them to `set_data`,
+// which is loaded as its own value. the file is read a line at a time,
+// a malformed line is an `InvalidData` error
+fn read_import(
+    path: &Path,
+) -> io::Result<impl Iterator<Item = io::Result<(Vec<u8>, Vec<u8>)>>> {
+    let file = io::BufReader::new(std::fs::File::open(path)?);
+    Ok(file.lines().filter_map(|line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            None
+        } else if line.starts_with('{') {
+            Some(
+                serde_json::from_str::<ImportLine>(line)
+                    .map(|kv| (kv.key.into_bytes(), kv.value.into_bytes()))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            )
+        } else {
+            Some(Ok((line.as_bytes().to_vec(), line.as_bytes().to_vec())))
+        }
+    }))
+}
+
+// the percent encoded `name` inside `dir`, the directory the admin routes
+// are confined to, `None` for an absolute path or one climbing out of it
+fn admin_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let name = String::from_utf8(percent_decode(name)).ok()?;
+    let name = Path::new(&name);
+    let mut parts = name.components().peekable();
+    parts.peek()?;
+    if parts.all(|part| matches!(part, Component::Normal(_))) {
+        Some(dir.join(name))
+    } else {
+        None
+    }
+}
+
+// the `durability` query parameter, or else the `X-Durability` header,
+// `Err` for a value other than memory, wal and sync
+fn durability(req: &Request, query: &str) -> Result<Option<Durability>, ()> {
+    let value = match query_param(query, "durability") {
+        Some(value) => value.as_bytes(),
+        None => match req.header("X-Durability") {
+            Some(value) => value,
+            None => return Ok(None),
+        },
+    };
+    match value {
+        b"memory" => Ok(Some(Durability::Memory)),
+        b"wal" => Ok(Some(Durability::Wal)),
+        b"sync" => Ok(Some(Durability::Sync)),
+        _ => Err(()),
+    }
+}
+
+// a namespace name from the path, `None` if it isn't utf-8
+fn namespace_name(s: &str) -> Option<String> {
+    String::from_utf8(percent_decode(s)).ok()
+}
+
+// the namespace `?ns=` names, the default one without it,
+// `None` if there is no such namespace
+fn ns_param(rocks: &RocksKvUtil, query: &str) -> Option<RocksKvUtil> {
+    match query_param(query, "ns").map(namespace_name) {
+        None => Some(rocks.clone()),
+        Some(Some(name)) => rocks.namespace(&name),
+        Some(None) => None,
+    }
+}
+
+impl<K: Engine> Techempower<K> {
+    // the operator routes, all of them need the RocksDB engine
+    fn admin(
+        &self,
+        req: &Request,
+        path: &str,
+        query: &str,
+        rsp: &mut Response,
+    ) -> io::Result<()> {
+        let rocks = match self.kv.rocks() {
+            Some(rocks) => rocks,
+            None => {
+                rsp.status_code("501", "Not Implemented");
+                return Ok(());
+            }
+        };
+        if path == "/admin/ns" {
+            serde_json::to_writer(BodyWriter(rsp.body_mut()), &rocks.namespaces()?)?;
+            rsp.header("Content-Type: application/json");
+        }
+        else if path.starts_with("/admin/ns/create/") {
+            // the body is a json `NamespaceOpts`, an empty one keeps every default
+            let name = match namespace_name(&path[17..]) {
+                Some(name) if !name.is_empty() => name,
+                _ => {
+                    rsp.status_code("400", "Bad Request");
+                    return Ok(());
+                }
+            };
+            let opts: NamespaceOpts = if req.body_().is_empty() {
+                NamespaceOpts::default()
+            } else {
+                json_body!(req, rsp)
+            };
+            if rocks.namespace(&name).is_some() {
+                rsp.status_code("409", "Conflict");
+                return Ok(());
+            }
+            rocks.create_namespace(&name, &opts)?;
+        }
+        else if path.starts_with("/admin/ns/drop/") {
+            let name = namespace_name(&path[15..]).unwrap_or_default();
+            match rocks.drop_namespace(&name) {
+                Err(StorageError::UnknownNamespace(_)) => {
+                    rsp.status_code("404", "Not Found");
+                }
+                r => r?,
+            }
+        }
+        else if path == "/admin/checkpoint" {
+            // ?dir= names a directory in the files directory that doesn't
+            // exist yet
+            let dir = match query_param(query, "dir").and_then(|d| admin_path(&self.files, d)) {
+                Some(dir) => dir,
+                None => {
+                    rsp.status_code("400", "Bad Request");
+                    return Ok(());
+                }
+            };
+            if dir.exists() {
+                rsp.status_code("409", "Conflict");
+                return Ok(());
+            }
+            rocks.checkpoint(&dir)?;
+        }
+        else if path == "/admin/import" {
+            // ?file= is a file in the files directory, see `read_import`,
+            // ?ns= loads it into a namespace, answers the number of values.
+            // the file is checked whole before anything is loaded, a
+            // malformed line answers 400 with the error
+            let file = query_param(query, "file").and_then(|f| admin_path(&self.files, f));
+            let (file, target) = match (file, ns_param(rocks, query)) {
+                (Some(file), Some(target)) => (file, target),
+                _ => {
+                    rsp.status_code("400", "Bad Request");
+                    return Ok(());
+                }
+            };
+            let entries = match read_import(&file) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    rsp.status_code("404", "Not Found");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                match entry {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                        rsp.status_code("400", "Bad Request");
+                        rsp.body_vec(e.to_string().into_bytes());
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            let count = target.ingest(read_import(&file)?)?;
+            number_body(rsp, count);
+        }
+        else if path == "/admin/export" {
+            // ?prefix= and ?ns= pick what to export, with ?file= the lines
+            // stream to that new file in the files directory (409 if it
+            // exists, 400 for a path leaving the directory) and
+            // the line count is answered. without it they are the response
+            // body, which the server sends with a content length, so it is
+            // built in memory and refused with 413 past `EXPORT_BODY_LIMIT`
+            let prefix = query_param(query, "prefix").map(percent_decode).unwrap_or_default();
+            let target = match ns_param(rocks, query) {
+                Some(target) => target,
+                None => {
+                    rsp.status_code("404", "Not Found");
+                    return Ok(());
+                }
+            };
+            match query_param(query, "file").map(|f| admin_path(&self.files, f)) {
+                Some(None) => {
+                    rsp.status_code("400", "Bad Request");
+                }
+                Some(Some(file)) => {
+                    let file = std::fs::OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(file);
+                    let file = match file {
+                        Ok(file) => file,
+                        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                            rsp.status_code("409", "Conflict");
+                            return Ok(());
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    let mut out = io::BufWriter::new(file);
+                    let lines = write_export(&target, &prefix, &mut out)?;
+                    io::Write::flush(&mut out)?;
+                    number_body(rsp, lines);
+                }
+                None => {
+                    let mut out = CappedBody {
+                        body: BodyWriter(rsp.body_mut()),
+                        left: EXPORT_BODY_LIMIT,
+                        exceeded: false
+                    };
+                    match write_export(&target, &prefix, &mut out) {
+                        Err(_) if out.exceeded => {
+                            rsp.body_mut().clear();
+                            rsp.status_code("413", "Payload Too Large");
+                            rsp.body("export too large, pass ?file=");
+                        }
+                        lines => {
+                            lines?;
+                            rsp.header("Content-Type: application/x-ndjson");
+                        }
+                    }
+                }
+            }
+        }
+        else if matches!(path, "/admin/compact" | "/admin/flush" | "/admin/delete_range") {
+            // ?ns= picks the namespace, ?start= and ?end= (percent encoded,
+            // `end` excluded) the string keys of `/compact` and `/delete_range`.
+            // `/compact` with neither compacts the whole namespace,
+            // `/delete_range` wants at least one of them
+            let target = match ns_param(rocks, query) {
+                Some(target) => target,
+                None => {
+                    rsp.status_code("404", "Not Found");
+                    return Ok(());
+                }
+            };
+            let start = query_param(query, "start").map(percent_decode);
+            let end = query_param(query, "end").map(percent_decode);
+            let (start, end) = (start.as_deref(), end.as_deref());
+            match path {
+                "/admin/compact" => target.compact_range(start, end)?,
+                "/admin/flush" => target.flush()?,
+                _ if start.is_none() && end.is_none() => {
+                    rsp.status_code("400", "Bad Request");
+                }
+                _ => target.delete_range(start, end)?,
+            }
+        }
+        else if path.starts_with("/admin/backups") {
+            return self.admin_backups(path, query, rsp);
+        }
+        else {
+            rsp.status_code("404", "Not Found");
+        }
+        Ok(())
+    }
+
+    // `/admin/backups` lists, `/create` takes one now, `/verify/{id}`
+    // and `/restore/{id}?dir=` work on the backup `id`, restoring into
+    // the files directory
+    fn admin_backups(&self, path: &str, query: &str, rsp: &mut Response) -> io::Result<()> {
+        let backups = match self.backups.as_ref() {
+            Some(backups) => backups,
+            None => {
+                rsp.status_code("501", "Not Implemented");
+                return Ok(());
+            }
+        };
+        if path == "/admin/backups" {
+            let list: Vec<BackupEntry> = backups
+                .list()
+                .into_iter()
+                .map(|b| BackupEntry {
+                    id: b.id,
+                    timestamp: b.timestamp,
+                    size: b.size,
+                    num_files: b.num_files
+                })
+                .collect();
+            serde_json::to_writer(BodyWriter(rsp.body_mut()), &list)?;
+            rsp.header("Content-Type: application/json");
+        }
+        else if path == "/admin/backups/create" {
+            number_body(rsp, backups.create()?);
+        }
+        else if path.starts_with("/admin/backups/verify/") {
+            let id = match path[22..].parse() {
+                Ok(id) => id,
+                Err(_) => {
+                    rsp.status_code("400", "Bad Request");
+                    return Ok(());
+                }
+            };
+            // a broken backup is reported in the body rather than as a 500
+            if let Err(e) = backups.verify(id) {
+                rsp.status_code("422", "Unprocessable Entity");
+                rsp.body_vec(e.to_string().into_bytes());
+            }
+        }
+        else if path.starts_with("/admin/backups/restore/") {
+            // ?dir= is a new directory in the files directory
+            let id = path[23..].parse().ok();
+            let dir = query_param(query, "dir").and_then(|d| admin_path(&self.files, d));
+            let (id, dir) = match (id, dir) {
+                (Some(id), Some(dir)) => (id, dir),
+                _ => {
+                    rsp.status_code("400", "Bad Request");
+                    return Ok(());
+                }
+            };
+            if dir.exists() {
+                rsp.status_code("409", "Conflict");
+                return Ok(());
+            }
+            backups.restore(id, &dir)?;
+        }
+        else {
+            rsp.status_code("404", "Not Found");
+        }
+        Ok(())
+    }
+
+    // the data routes, on the namespace picked for the request
+    fn route(
+        kv: &K,
+        req: &Request,
+        path: &str,
+        query: &str,
+        rsp: &mut Response,
+    ) -> io::Result<()> {
+        if path == "/init" {
             rsp.header("Content-Type: text/plain").body("ok");
         }
-        else if req.path().starts_with("/query/") {
-            let key = &req.path()[7..];
-            let val = self.kv.get(key);
-            let b = rsp.body_mut();
-            b.write_str(val).unwrap(); // TODO err handle
-            // println!("key is {}, val is {}", key, val);
-            rsp.header("Content-Type: text/plain");
-        }
-        else if req.path() == "/add" {
-            let r_body = req.body_();
-            // println!("body is {}", std::str::from_utf8(&r_body.to_vec()).unwrap());
-            let kv: KeyValue = serde_json::from_slice(r_body).unwrap();  // FIXME 处理异常         
-            self.kv.set(kv.key, kv.value);
-            // println!("to add key is {}, value is {}", kv.key, kv.value);
-        }
-        else if req.path().starts_with("/del/") {
-            let key = &req.path()[7..];
-            self.kv.remove(key);
+        else if path.starts_with("/query/") {
+            // raw bytes for `Accept: application/octet-stream` or non utf-8 values
+            let key = percent_decode(&path[7..]);
+            match kv.get(&key)? {
+                Some(val) => {
+                    let binary = req
+                        .header("Accept")
+                        .is_some_and(|a| a == b"application/octet-stream");
+                    value_body(rsp, val, binary);
+                }
+                None => {
+                    rsp.status_code("404", "Not Found");
+                }
+            }
+        }
+        else if path == "/add" {
+            // answers the new version of the key
+            let kv: KeyValue = json_body!(req, rsp);
+            let ttl = ttl_param!(query, rsp);
+            let version = kv.set(kv.key.as_bytes(), kv.value.as_bytes(), ttl)?;
+            number_body(rsp, version);
+        }
+        else if path.starts_with("/add/") {
+            // the raw (application/octet-stream) body is stored as the value
+            let key = percent_decode(&path[5..]);
+            let ttl = ttl_param!(query, rsp);
+            let version = kv.set(&key, req.body_(), ttl)?;
+            number_body(rsp, version);
+        }
+        else if path.starts_with("/getv/") {
+            let key = percent_decode(&path[6..]);
+            match kv.get_versioned(&key)? {
+                Some((val, version)) => {
+                    let resp = VersionedValue {
+                        value: String::from_utf8_lossy(&val),
+                        version
+                    };
+                    serde_json::to_writer(BodyWriter(rsp.body_mut()), &resp)?;
+                    rsp.header("Content-Type: application/json");
+                }
+                None => {
+                    rsp.status_code("404", "Not Found");
+                }
+            }
+        }
+        else if path == "/cas" {
+            let cas: CasRequest = json_body!(req, rsp);
+            let expected = match (cas.version, cas.expected) {
+                (Some(version), None) => Expected::Version(version),
+                (None, Some(old)) => Expected::Value(old.as_bytes()),
+                _ => {
+                    rsp.status_code("400", "Bad Request");
+                    return Ok(());
+                }
+            };
+            let query_ttl = ttl_param!(query, rsp);
+            let ttl = cas.ttl_ms.map(Duration::from_millis).or(query_ttl);
+            match kv.cas(cas.key.as_bytes(), expected, cas.value.as_bytes(), ttl)? {
+                Some(version) => number_body(rsp, version),
+                None => {
+                    rsp.status_code("409", "Conflict");
+                }
+            }
+        }
+        else if path.starts_with("/cas/") {
+            // raw body form, the expected version is the `version` query parameter
+            let key = percent_decode(&path[5..]);
+            let version = match query_param(query, "version").and_then(|v| v.parse().ok()) {
+                Some(version) => version,
+                None => {
+                    rsp.status_code("400", "Bad Request");
+                    return Ok(());
+                }
+            };
+            let ttl = ttl_param!(query, rsp);
+            match kv.cas(&key, Expected::Version(version), req.body_(), ttl)? {
+                Some(version) => number_body(rsp, version),
+                None => {
+                    rsp.status_code("409", "Conflict");
+                }
+            }
+        }
+        else if path.starts_with("/del/") {
+            let key = percent_decode(&path[5..]);
+            kv.remove(&key)?;
             // println!("del key is {}", key);
         }
-        else if req.path() == "/list" {
-            let r_body = req.body_();
-            let keys: Vec<&str> = serde_json::from_slice(r_body).unwrap();  // FIXME 处理异常         
-            
-            let vals = self.kv.mget(&keys);
-
-            let mut resp = Vec::<KeyValue>::new();
-            let mut i = 0;
-            while i < vals.len() {
-                let item = KeyValue {
-                    key: keys[i],
-                    value: vals[i]
-                };
-                resp.push(item);
-                i = i + 1;
-            }
-            let resp_body = serde_json::to_string(&resp).unwrap();
-            let b = rsp.body_mut();
-            b.write_str(resp_body.as_str()).unwrap(); // TODO err handle
+        else if path == "/list" {
+            let keys: Vec<&str> = json_body!(req, rsp);
+            let raw_keys: Vec<&[u8]> = keys.iter().map(|k| k.as_bytes()).collect();
+
+            let vals = kv.mget(&raw_keys)?;
+
+            // the json form is text only, binary values come back lossy
+            let resp: Vec<KeyMaybeValue> = keys
+                .iter()
+                .zip(vals.iter())
+                .map(|(key, val)| KeyMaybeValue {
+                    key,
+                    value: val.as_ref().map(|v| String::from_utf8_lossy(v))
+                })
+                .collect();
+            serde_json::to_writer(BodyWriter(rsp.body_mut()), &resp)?;
 
             rsp.header("Content-Type: application/json");
         }
-        else if req.path() == "/batch" {
-            let r_body = req.body_();
-            // println!("body is {}", std::str::from_utf8(&r_body.to_vec()).unwrap());
-
-            let kv: Vec<KeyValue> = serde_json::from_slice(r_body).unwrap();  // FIXME 处理异常         
-            let mut keys = Vec::new();
-            let mut vals = Vec::new();
-            for p in kv.iter() {
-                keys.push(p.key);
-                vals.push(p.value);
+        else if path == "/batch" {
+            // all the entries commit together or not at all, answers the
+            // new version for each set entry and null for the others
+            let entries: Vec<BatchEntry> = json_body!(req, rsp);
+            let ttl = ttl_param!(query, rsp);
+            let ops: Option<Vec<BatchOp>> = entries.iter().map(|e| e.to_op(ttl)).collect();
+            match ops {
+                Some(ops) => {
+                    let versions = kv.batch(&ops)?;
+                    serde_json::to_writer(BodyWriter(rsp.body_mut()), &versions)?;
+                    rsp.header("Content-Type: application/json");
+                }
+                None => {
+                    rsp.status_code("400", "Bad Request");
+                }
             }
+        }
+        else if path.starts_with("/zadd/") {
+            let key = percent_decode(&path[6..]);
+            let z_val: ZValue = json_body!(req, rsp);
+            let ttl = ttl_param!(query, rsp);
 
-            self.kv.mset(&keys, &vals);
+            kv.zadd(&key, z_val.value.as_bytes(), z_val.score, ttl)?;
+        }
+        else if path.starts_with("/zrange/") {
+            let key = percent_decode(&path[8..]);
+            let z_score: ZRangeScore = json_body!(req, rsp);
+
+            let members = kv.zrange(&key, &z_score.to_range())?;
+            let resp: Vec<ZMember> = members
+                .iter()
+                .map(|(member, score)| ZMember {
+                    member: String::from_utf8_lossy(member),
+                    score: *score
+                })
+                .collect();
+            serde_json::to_writer(BodyWriter(rsp.body_mut()), &resp)?;
+
+            rsp.header("Content-Type: application/json");
         }
-        else if req.path().starts_with("/zadd/") {
-            let key = &req.path()[6..];
-            let r_body = req.body_();
-            // println!("key is {}, body is {}", key, std::str::from_utf8(&r_body.to_vec()).unwrap());
-            let z_val: ZValue = serde_json::from_slice(r_body).unwrap();  // FIXME 处理异常
+        else if path.starts_with("/zrmv/") {
+            let key_and_value = &path[6..];
+            match key_and_value.split_once('/') {
+                Some((key, val)) => kv.zrmv(&percent_decode(key), &percent_decode(val))?,
+                None => {
+                    rsp.status_code("400", "Bad Request");
+                }
+            }
+        }
+        else if path.starts_with("/hset/") {
+            // the raw body is the value, answers 1 for a new field, else 0
+            match path[6..].split_once('/') {
+                Some((key, field)) => {
+                    let added = kv.hset(&percent_decode(key), &percent_decode(field), req.body_())?;
+                    number_body(rsp, added as u8);
+                }
+                None => {
+                    rsp.status_code("400", "Bad Request");
+                }
+            }
+        }
+        else if path.starts_with("/hget/") {
+            match path[6..].split_once('/') {
+                Some((key, field)) => match kv.hget(&percent_decode(key), &percent_decode(field))? {
+                    Some(val) => value_body(rsp, val, false),
+                    None => {
+                        rsp.status_code("404", "Not Found");
+                    }
+                },
+                None => {
+                    rsp.status_code("400", "Bad Request");
+                }
+            }
+        }
+        else if path.starts_with("/hgetall/") {
+            let key = percent_decode(&path[9..]);
+            let fields = kv.hgetall(&key)?;
+            let resp: Vec<HField> = fields
+                .iter()
+                .map(|(field, value)| {
+                    let (field, field_hex) = text_or_hex(field);
+                    let (value, value_hex) = text_or_hex(value);
+                    HField { field, field_hex, value, value_hex }
+                })
+                .collect();
+            serde_json::to_writer(BodyWriter(rsp.body_mut()), &resp)?;
 
-            self.kv.zadd(key, z_val.value, &z_val.score);
+            rsp.header("Content-Type: application/json");
+        }
+        else if path.starts_with("/hdel/") {
+            match path[6..].split_once('/') {
+                Some((key, field)) => {
+                    if !kv.hdel(&percent_decode(key), &percent_decode(field))? {
+                        rsp.status_code("404", "Not Found");
+                    }
+                }
+                None => {
+                    rsp.status_code("400", "Bad Request");
+                }
+            }
+        }
+        else if path.starts_with("/hlen/") {
+            let key = percent_decode(&path[6..]);
+            let len = kv.hlen(&key)?;
+            number_body(rsp, len);
+        }
+        else if path.starts_with("/lpush/") || path.starts_with("/rpush/") {
+            // the raw body is the element, answers the new length
+            let key = percent_decode(&path[7..]);
+            let len = if path.starts_with("/lpush/") {
+                kv.lpush(&key, req.body_())?
+            } else {
+                kv.rpush(&key, req.body_())?
+            };
+            number_body(rsp, len);
+        }
+        else if path.starts_with("/lpop/") || path.starts_with("/rpop/") {
+            let key = percent_decode(&path[6..]);
+            let popped = if path.starts_with("/lpop/") {
+                kv.lpop(&key)?
+            } else {
+                kv.rpop(&key)?
+            };
+            match popped {
+                Some(val) => value_body(rsp, val, false),
+                None => {
+                    rsp.status_code("404", "Not Found");
+                }
+            }
+        }
+        else if path.starts_with("/lrange/") || path.starts_with("/ltrim/") {
+            // ?start= and ?stop= are included, negative ones count back
+            // from the tail, they default to the whole list (0 and -1).
+            // `/lrange` answers a json array of `ListElement`
+            let index = |name, default| match query_param(query, name) {
+                Some(i) => i.parse().ok(),
+                None => Some(default),
+            };
+            let (start, stop) = match (index("start", 0), index("stop", -1)) {
+                (Some(start), Some(stop)) => (start, stop),
+                _ => {
+                    rsp.status_code("400", "Bad Request");
+                    return Ok(());
+                }
+            };
+            if path.starts_with("/ltrim/") {
+                kv.ltrim(&percent_decode(&path[7..]), start, stop)?;
+            } else {
+                let items = kv.lrange(&percent_decode(&path[8..]), start, stop)?;
+                let resp: Vec<ListElement> = items
+                    .iter()
+                    .map(|item| {
+                        let (value, value_hex) = text_or_hex(item);
+                        ListElement { value, value_hex }
+                    })
+                    .collect();
+                serde_json::to_writer(BodyWriter(rsp.body_mut()), &resp)?;
+                rsp.header("Content-Type: application/json");
+            }
+        }
+        else if path.starts_with("/llen/") {
+            let key = percent_decode(&path[6..]);
+            let len = kv.llen(&key)?;
+            number_body(rsp, len);
+        }
+        else if path == "/stats" {
+            // the namespace's `EngineStats`, the tickers are database wide
+            let rocks = match kv.rocks() {
+                Some(rocks) => rocks,
+                None => {
+                    rsp.status_code("501", "Not Implemented");
+                    return Ok(());
+                }
+            };
+            let stats = rocks.stats()?;
+            let stats = StatsBody {
+                block_cache_hit_rate: stats.block_cache_hit_rate(),
+                stats
+            };
+            serde_json::to_writer(BodyWriter(rsp.body_mut()), &stats)?;
+            rsp.header("Content-Type: application/json");
+        }
+        else if path == "/snapshot" {
+            // answers the token to pass as `snapshot` to `/scan`, held for
+            // `ttl_ms`, a minute by default and `MAX_SNAPSHOT_TTL` at most.
+            // 503 once `MAX_SNAPSHOTS` are held
+            let rocks = match kv.rocks() {
+                Some(rocks) => rocks,
+                None => {
+                    rsp.status_code("501", "Not Implemented");
+                    return Ok(());
+                }
+            };
+            let ttl = ttl_param!(query, rsp).unwrap_or(Duration::from_secs(60));
+            match rocks.create_snapshot(ttl) {
+                Err(StorageError::TooManySnapshots) => {
+                    rsp.status_code("503", "Service Unavailable");
+                }
+                token => number_body(rsp, token?),
+            }
+        }
+        else if path.starts_with("/snapshot/release/") {
+            let token = path[18..].parse().ok();
+            match (kv.rocks(), token) {
+                (None, _) => {
+                    rsp.status_code("501", "Not Implemented");
+                }
+                (Some(rocks), Some(token)) if rocks.release_snapshot(token) => {}
+                _ => {
+                    rsp.status_code("404", "Not Found");
+                }
+            }
         }
-        else if req.path().starts_with("/zrange/") {
-            let key = &req.path()[8..];
-            let r_body = req.body_();
-            println!("key is {}, body is {}", key, std::str::from_utf8(&r_body.to_vec()).unwrap());
-            let z_score: ZRangeScore = serde_json::from_slice(r_body).unwrap();  // FIXME 处理异常
+        else if path == "/scan" {
+            // ?start=&end=&prefix=&match=&limit=&cursor=, keys percent encoded,
+            // pages read with the same `snapshot=` token see the same data
+            let param = |name| query_param(query, name).map(percent_decode);
+            let cursor = match query_param(query, "cursor").map(hex_decode) {
+                Some(None) => {
+                    rsp.status_code("400", "Bad Request");
+                    return Ok(());
+                }
+                Some(cursor) => cursor,
+                None => None,
+            };
+            let opts = ScanOpts {
+                start: param("start"),
+                end: param("end"),
+                prefix: param("prefix"),
+                pattern: param("match"),
+                limit: query_param(query, "limit")
+                    .and_then(|l| l.parse().ok())
+                    .unwrap_or(100),
+                cursor,
+            };
+            let page = match query_param(query, "snapshot") {
+                None => kv.scan(&opts)?,
+                Some(token) => {
+                    let (rocks, token) = match (kv.rocks(), token.parse()) {
+                        (Some(rocks), Ok(token)) => (rocks, token),
+                        (None, _) => {
+                            rsp.status_code("501", "Not Implemented");
+                            return Ok(());
+                        }
+                        (_, Err(_)) => {
+                            rsp.status_code("400", "Bad Request");
+                            return Ok(());
+                        }
+                    };
+                    match rocks.scan_snapshot(token, &opts) {
+                        Err(StorageError::UnknownSnapshot(_)) => {
+                            rsp.status_code("404", "Not Found");
+                            return Ok(());
+                        }
+                        page => page?,
+                    }
+                }
+            };
+            let resp = ScanResult {
+                entries: page
+                    .entries
+                    .iter()
+                    .map(|(k, v)| {
+                        let (key, key_hex) = text_or_hex(k);
+                        let (value, value_hex) = text_or_hex(v);
+                        ScanEntry { key, key_hex, value, value_hex }
+                    })
+                    .collect(),
+                cursor: page.cursor.as_deref().map(hex_encode)
+            };
+            serde_json::to_writer(BodyWriter(rsp.body_mut()), &resp)?;
 
-            self.kv.zrange(key, &z_score.min_score, &&z_score.max_score);
+            rsp.header("Content-Type: application/json");
+        }
+        else if path.starts_with("/zscore/") {
+            match path[8..].split_once('/') {
+                Some((key, val)) => {
+                    match kv.zscore(&percent_decode(key), &percent_decode(val))? {
+                        Some(score) => number_body(rsp, score),
+                        None => {
+                            rsp.status_code("404", "Not Found");
+                        }
+                    }
+                }
+                None => {
+                    rsp.status_code("400", "Bad Request");
+                }
+            }
+        }
+        else if path.starts_with("/zcard/") {
+            let key = percent_decode(&path[7..]);
+            let card = kv.zcard(&key)?;
+            number_body(rsp, card);
+        }
+        else if path.starts_with("/zcount/") {
+            // takes the same body as `/zrange/`, paging fields are ignored
+            let key = percent_decode(&path[8..]);
+            let z_score: ZRangeScore = json_body!(req, rsp);
+            let count = kv.zcount(&key, &z_score.to_range())?;
+            number_body(rsp, count);
+        }
+        else if path.starts_with("/zrank/") {
+            match path[7..].split_once('/') {
+                Some((key, val)) => {
+                    match kv.zrank(&percent_decode(key), &percent_decode(val))? {
+                        Some(rank) => number_body(rsp, rank),
+                        None => {
+                            rsp.status_code("404", "Not Found");
+                        }
+                    }
+                }
+                None => {
+                    rsp.status_code("400", "Bad Request");
+                }
+            }
+        }
+        else if path.starts_with("/zincrby/") {
+            let key = percent_decode(&path[9..]);
+            let incr: ZIncr = json_body!(req, rsp);
+            let score = kv.zincrby(&key, incr.value.as_bytes(), incr.delta)?;
+            number_body(rsp, score);
         }
-        else if req.path().starts_with("/zrmv/") {
-            let keyAndValue = &req.path()[6..];
-            let splits: Vec<&str> = keyAndValue.split('/').collect();
-            // println!("key is {}, val is {}", splits[0], splits[1]);
-            self.kv.zrmv(splits[0], splits[1]);
+        else if path.starts_with("/append/") {
+            // the raw body goes after the current value, answers the new version
+            let key = percent_decode(&path[8..]);
+            let version = kv.append(&key, req.body_())?;
+            number_body(rsp, version);
+        }
+        else if path.starts_with("/incr/") || path.starts_with("/decr/") {
+            // `by` defaults to 1, answers the new count,
+            // or 409 when the value isn't a counter
+            let key = percent_decode(&path[6..]);
+            let delta = match query_param(query, "by") {
+                Some(by) => match by.parse() {
+                    Ok(by) => by,
+                    Err(_) => {
+                        rsp.status_code("400", "Bad Request");
+                        return Ok(());
+                    }
+                },
+                None => 1,
+            };
+            let count = if path.starts_with("/incr/") {
+                kv.incr(&key, delta)
+            } else {
+                kv.decr(&key, delta)
+            };
+            match count {
+                Err(StorageError::NotACounter) => {
+                    rsp.status_code("409", "Conflict");
+                }
+                count => number_body(rsp, count?),
+            }
         }
         else {
             rsp.status_code("404", "Not Found");
@@ -128,29 +1293,149 @@ impl HttpService for Techempower {
     }
 }
 
-struct HttpServer {}
+impl<K: Engine> HttpService for Techempower<K> {
+
+    fn call(&mut self, req: Request, rsp: &mut Response) -> io::Result<()> {
+        // Bare-bones router
+        // storage errors are turned into `io::Error` by `?` and answered with 500
+        let (path, query) = req.path().split_once('?').unwrap_or((req.path(), ""));
+        if path.starts_with("/admin/") {
+            return self.admin(&req, path, query, rsp);
+        }
+
+        // the namespace comes from a `/ns/{name}` path prefix or the
+        // `X-Namespace` header, without either it is the default one
+        let (ns, path) = if path.starts_with("/ns/") {
+            let rest = &path[4..];
+            let (name, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            (Some(namespace_name(name)), path)
+        } else {
+            let name = req
+                .header("X-Namespace")
+                .map(|name| String::from_utf8(name.to_vec()).ok());
+            (name, path)
+        };
+        let scoped;
+        let kv = match ns {
+            None => &self.kv,
+            Some(None) => {
+                rsp.status_code("400", "Bad Request");
+                return Ok(());
+            }
+            Some(Some(_)) if self.kv.rocks().is_none() => {
+                rsp.status_code("501", "Not Implemented");
+                return Ok(());
+            }
+            Some(Some(name)) => match self.kv.namespace(&name) {
+                Some(ns) => {
+                    scoped = ns;
+                    &scoped
+                }
+                None => {
+                    rsp.status_code("404", "Not Found");
+                    return Ok(());
+                }
+            },
+        };
+
+        // how durable the writes of the request are, for `/add`, `/batch`,
+        // `/zadd` and the other write routes alike
+        let durable;
+        let kv = match durability(&req, query) {
+            Ok(None) => kv,
+            Ok(Some(d)) => {
+                durable = kv.with_durability(d);
+                &durable
+            }
+            Err(()) => {
+                rsp.status_code("400", "Bad Request");
+                return Ok(());
+            }
+        };
+        Self::route(kv, &req, path, query, rsp)
+    }
+}
+
+struct HttpServer<K> {
+    kv: K,
+    backups: Option<Backups>,
+    files: PathBuf
+}
 
-impl HttpServiceFactory for HttpServer {
-    type Service = Techempower;
+impl<K: Engine> HttpServiceFactory for HttpServer<K> {
+    type Service = Techempower<K>;
 
     fn new_service(&self) -> Self::Service {
-        let kv_util_impl = MockKvUtil {};
-        Techempower { kv: kv_util_impl }
+        Techempower {
+            kv: self.kv.clone(),
+            backups: self.backups.clone(),
+            files: self.files.clone()
+        }
     }
 }
 
+// backups are on when `BACKUP_DIR` is set, `BACKUP_INTERVAL_SECS`
+// (an hour), `BACKUP_KEEP` (7) and `BACKUP_VERIFY` (true) tune them
+fn backup_policy() -> Option<BackupPolicy> {
+    let env = |name| std::env::var(name).ok();
+    Some(BackupPolicy {
+        dir: env("BACKUP_DIR")?.into(),
+        interval: Duration::from_secs(
+            env("BACKUP_INTERVAL_SECS").and_then(|s| s.parse().ok()).unwrap_or(3600),
+        ),
+        keep: env("BACKUP_KEEP").and_then(|s| s.parse().ok()).unwrap_or(7),
+        verify: env("BACKUP_VERIFY").as_deref() != Some("false"),
+    })
+}
+
 fn main() {
-    // init rocksdb
-    // let mut db = 
-    //     DB::open_default("C:/Users/txcjh/Desktop/Projects/may_minihttp/storage").unwrap();
-    // db.put(b"my key", b"my value");
-    // let resp = db.get("my key").unwrap().unwrap();
-    // println!("value is {}", std::str::from_utf8(resp.as_ref()).unwrap());
-    
+    // usage: hi_rust_rocks [storage dir]
+    //            [rocksdb|leveldb|restore <checkpoint dir>|import <file>]
+    //
+    // `restore` replaces the storage dir with a copy of the checkpoint
+    // made by `/admin/checkpoint`, then serves it with rocksdb.
+    // `import` bulk loads the file (see `read_import`) and exits
+    //
+    // the `CONFIG` env var names a json config file (see `ServerConfig`),
+    // `FILES_DIR` the only directory the admin routes read and write files
+    // in, `{storage dir}.files` by default
+    let path = std::env::args().nth(1).unwrap_or_else(|| "storage".to_owned());
+    let engine = std::env::args().nth(2).unwrap_or_else(|| "rocksdb".to_owned());
+    let config = server_config().unwrap().engine;
+    if engine == "restore" {
+        let checkpoint = std::env::args().nth(3).expect("restore needs a checkpoint dir");
+        RocksKvUtil::restore_checkpoint(&checkpoint, &path).unwrap();
+    }
+    if engine == "import" {
+        let file = std::env::args().nth(3).expect("import needs a file");
+        let kv = RocksKvUtil::open_with(&path, &config).unwrap();
+        let count = kv.ingest(read_import(Path::new(&file)).unwrap()).unwrap();
+        println!("imported {} keys", count);
+        return;
+    }
+
+    let files = std::env::var("FILES_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| format!("{}.files", path).into());
+    std::fs::create_dir_all(&files).unwrap();
+
+    // rocksdb calls need a much deeper stack than the bare http path
     may::config()
         .set_pool_capacity(10000)
-        .set_stack_size(0x1000);
-    let http_server = HttpServer {};
-    let server = http_server.start("0.0.0.0:8081").unwrap();
+        .set_stack_size(0x10000);
+    let server = match engine.as_str() {
+        "leveldb" => {
+            let kv = LevelDbKvUtil::open(&path).unwrap();
+            HttpServer { kv, backups: None, files }.start("0.0.0.0:8081").unwrap()
+        }
+        _ => {
+            let kv = RocksKvUtil::open_with(&path, &config).unwrap();
+            let backups = backup_policy().map(|policy| Backups::open(&kv, policy).unwrap());
+            if let Some(backups) = backups.as_ref() {
+                backups.schedule();
+            }
+            HttpServer { kv, backups, files }.start("0.0.0.0:8081").unwrap()
+        }
+    };
     server.join().unwrap();
 }
\ No newline at end of file
@@ -0,0 +1,131 @@
+//! incremental backups of a RocksDB database
+//!
+//! backups share the sst files they have in common, so each one only
+//! copies what changed since the one before. a coroutine takes them on
+//! a schedule, the way `date.rs` refreshes the date header
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use may::sync::Mutex;
+use rocksdb::backup::{BackupEngine, BackupEngineOptions, RestoreOptions};
+use rocksdb::Env;
+
+use crate::error::StorageError;
+use crate::kv_util::RocksKvUtil;
+
+/// where backups go, how often they are taken and how many are kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupPolicy {
+    pub dir: PathBuf,
+    /// time between two scheduled backups
+    pub interval: Duration,
+    /// the number of most recent backups kept, older ones are purged
+    pub keep: usize,
+    /// check every new backup is complete
+    pub verify: bool,
+}
+
+/// one of the backups `Backups::list` finds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: u32,
+    /// unix seconds the backup was taken at
+    pub timestamp: i64,
+    /// bytes in the backup, the files shared with others included
+    pub size: u64,
+    pub num_files: u32,
+}
+
+/// the backups of one database, taken by `BackupPolicy`
+///
+/// clones share the same backup directory
+#[derive(Clone)]
+pub struct Backups {
+    kv: RocksKvUtil,
+    engine: Arc<Mutex<BackupEngine>>,
+    policy: BackupPolicy,
+}
+
+impl Backups {
+    /// open the backup directory of `policy` for the database of `kv`,
+    /// creating it if missing
+    pub fn open(kv: &RocksKvUtil, policy: BackupPolicy) -> Result<Self, StorageError> {
+        let opts = BackupEngineOptions::new(&policy.dir)?;
+        let engine = BackupEngine::open(&opts, &Env::new()?)?;
+        Ok(Backups {
+            kv: kv.clone(),
+            engine: Arc::new(Mutex::new(engine)),
+            policy,
+        })
+    }
+
+    /// take a backup every `BackupPolicy::interval` from now on
+    ///
+    /// the coroutine runs for as long as the process, failures are logged
+    pub fn schedule(&self) {
+        let backups = self.clone();
+        may::go!(move || loop {
+            may::coroutine::sleep(backups.policy.interval);
+            match backups.create() {
+                Ok(id) => info!("backup {} done", id),
+                Err(e) => error!("scheduled backup failed: {}", e),
+            }
+        });
+    }
+
+    /// back up every namespace now and return the id of the new backup,
+    /// then purge the backups the policy doesn't keep
+    pub fn create(&self) -> Result<u32, StorageError> {
+        let mut engine = self.engine.lock().unwrap();
+        // flushing first leaves nothing to replay from the wal on restore
+        engine.create_new_backup_flush(&*self.kv.db, true)?;
+        let id = engine
+            .get_backup_info()
+            .iter()
+            .map(|info| info.backup_id)
+            .max()
+            .unwrap_or(0);
+        if self.policy.verify {
+            engine.verify_backup(id)?;
+        }
+        engine.purge_old_backups(self.policy.keep.max(1))?;
+        Ok(id)
+    }
+
+    /// the backups in the directory, oldest first
+    pub fn list(&self) -> Vec<BackupInfo> {
+        self.engine
+            .lock()
+            .unwrap()
+            .get_backup_info()
+            .into_iter()
+            .map(|info| BackupInfo {
+                id: info.backup_id,
+                timestamp: info.timestamp,
+                size: info.size,
+                num_files: info.num_files,
+            })
+            .collect()
+    }
+
+    /// check the files of backup `id` are all there at the sizes recorded
+    pub fn verify(&self, id: u32) -> Result<(), StorageError> {
+        Ok(self.engine.lock().unwrap().verify_backup(id)?)
+    }
+
+    /// write the database as of backup `id` into `path`
+    ///
+    /// `path` can't be the live database, the restored copy is opened
+    /// in its place by restarting on it
+    pub fn restore<P: AsRef<Path>>(&self, id: u32, path: P) -> Result<(), StorageError> {
+        let path = path.as_ref();
+        let opts = RestoreOptions::default();
+        self.engine
+            .lock()
+            .unwrap()
+            .restore_from_backup(path, path, &opts, id)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+//! consistent copies of a live RocksDB database
+//!
+//! a checkpoint is a complete database directory, hard linking the sst
+//! files of the live one where it can, so it is cheap to take while the
+//! server keeps running and can be opened like any other database
+
+use std::fs;
+use std::path::Path;
+
+use rocksdb::checkpoint::Checkpoint;
+
+use crate::error::StorageError;
+use crate::kv_util::RocksKvUtil;
+
+impl RocksKvUtil {
+    /// write a checkpoint of every namespace into `dir`, which must not exist
+    pub fn checkpoint<P: AsRef<Path>>(&self, dir: P) -> Result<(), StorageError> {
+        Checkpoint::new(&self.db)?.create_checkpoint(dir)?;
+        Ok(())
+    }
+
+    /// replace the database at `path` with a copy of the checkpoint in `dir`
+    ///
+    /// the database must not be open. the copy is made next to `path`
+    /// first and the old database moved aside, not removed, until the
+    /// copy took its place, so a failed restore leaves it in place
+    pub fn restore_checkpoint<P: AsRef<Path>, Q: AsRef<Path>>(
+        dir: P,
+        path: Q,
+    ) -> Result<(), StorageError> {
+        let path = path.as_ref();
+        let mut staging = path.as_os_str().to_owned();
+        staging.push(".restoring");
+        if Path::new(&staging).exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+        // checkpoints are flat, a directory holds just files
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::copy(entry.path(), Path::new(&staging).join(entry.file_name()))?;
+            }
+        }
+        // the old database is only moved aside until the copy is in place,
+        // and moved back if that fails
+        let mut old = path.as_os_str().to_owned();
+        old.push(".old");
+        if Path::new(&old).exists() {
+            fs::remove_dir_all(&old)?;
+        }
+        let had_old = path.exists();
+        if had_old {
+            fs::rename(path, &old)?;
+        }
+        if let Err(e) = fs::rename(&staging, path) {
+            if had_old {
+                fs::rename(&old, path)?;
+            }
+            return Err(e.into());
+        }
+        // the restore is done, a leftover is only wasted space
+        if had_old {
+            if let Err(e) = fs::remove_dir_all(&old) {
+                error!("removing {} failed: {}", Path::new(&old).display(), e);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+//! tuning of the RocksDB engine
+
+use rocksdb::{BlockBasedOptions, Cache, DBCompressionType, Options};
+
+use crate::merge;
+
+/// a block compression algorithm, lowercase with the `serde` feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Compression {
+    None,
+    Snappy,
+    Zlib,
+    Bz2,
+    Lz4,
+    Lz4hc,
+    Zstd,
+}
+
+impl From<Compression> for DBCompressionType {
+    fn from(c: Compression) -> Self {
+        match c {
+            Compression::None => DBCompressionType::None,
+            Compression::Snappy => DBCompressionType::Snappy,
+            Compression::Zlib => DBCompressionType::Zlib,
+            Compression::Bz2 => DBCompressionType::Bz2,
+            Compression::Lz4 => DBCompressionType::Lz4,
+            Compression::Lz4hc => DBCompressionType::Lz4hc,
+            Compression::Zstd => DBCompressionType::Zstd,
+        }
+    }
+}
+
+/// how `RocksKvUtil::open_with` sets up the database,
+/// `None` and empty fields keep the RocksDB defaults
+///
+/// the column family options apply to every namespace,
+/// before its own `NamespaceOpts`
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct EngineConfig {
+    /// bytes of the lru cache for uncompressed blocks, shared by all namespaces
+    pub block_cache_size: Option<usize>,
+    /// bloom filter bits per key, lookups of missing keys skip most sst files
+    pub bloom_bits_per_key: Option<f64>,
+    /// keep index and filter blocks in the block cache instead of the heap
+    pub cache_index_and_filter_blocks: bool,
+    /// compression of each level, level 0 first
+    pub compression_per_level: Vec<Compression>,
+    /// bytes written to a memtable before it is flushed
+    pub write_buffer_size: Option<usize>,
+    pub max_write_buffer_number: Option<i32>,
+    /// bytes all the memtables together may hold
+    pub db_write_buffer_size: Option<usize>,
+    /// threads for flushes and compactions together
+    pub max_background_jobs: Option<i32>,
+    /// -1 keeps every sst file open
+    pub max_open_files: Option<i32>,
+}
+
+impl EngineConfig {
+    /// the options of the database as a whole
+    pub(crate) fn db_options(&self) -> Options {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        // the tickers `RocksKvUtil::stats` reports
+        opts.enable_statistics();
+        if let Some(size) = self.db_write_buffer_size {
+            opts.set_db_write_buffer_size(size);
+        }
+        if let Some(jobs) = self.max_background_jobs {
+            opts.set_max_background_jobs(jobs);
+        }
+        if let Some(files) = self.max_open_files {
+            opts.set_max_open_files(files);
+        }
+        opts
+    }
+
+    /// the options every column family is opened or created with,
+    /// `cache` is the block cache they share
+    pub(crate) fn cf_options(&self, cache: Option<&Cache>) -> Options {
+        let mut opts = Options::default();
+        opts.set_merge_operator(merge::MERGE_OPERATOR, merge::full_merge, merge::partial_merge);
+
+        let mut table = BlockBasedOptions::default();
+        if let Some(cache) = cache {
+            table.set_block_cache(cache);
+        }
+        if let Some(bits) = self.bloom_bits_per_key {
+            table.set_bloom_filter(bits, false);
+        }
+        table.set_cache_index_and_filter_blocks(self.cache_index_and_filter_blocks);
+        opts.set_block_based_table_factory(&table);
+
+        if !self.compression_per_level.is_empty() {
+            let levels: Vec<DBCompressionType> = self
+                .compression_per_level
+                .iter()
+                .map(|&c| c.into())
+                .collect();
+            opts.set_compression_per_level(&levels);
+        }
+        if let Some(size) = self.write_buffer_size {
+            opts.set_write_buffer_size(size);
+        }
+        if let Some(n) = self.max_write_buffer_number {
+            opts.set_max_write_buffer_number(n);
+        }
+        opts
+    }
+}
@@ -0,0 +1,64 @@
+use std::{error, fmt, io};
+
+/// errors reported by the `KvUtil` storage engines
+#[derive(Debug)]
+pub enum StorageError {
+    /// the RocksDB engine failed
+    Rocks(rocksdb::Error),
+    /// the LevelDB engine failed
+    LevelDb(rusty_leveldb::Status),
+    /// a stored record could not be decoded
+    Corrupted(String),
+    /// the namespace doesn't exist, or was dropped
+    UnknownNamespace(String),
+    /// the snapshot token was released, expired or never handed out
+    UnknownSnapshot(u64),
+    /// `RocksKvUtil::create_snapshot` with `MAX_SNAPSHOTS` already held
+    TooManySnapshots,
+    /// `KvUtil::incr` on a value that isn't a decimal integer
+    NotACounter,
+    /// copying database files failed
+    Io(io::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Rocks(e) => write!(f, "rocksdb error: {}", e),
+            StorageError::LevelDb(e) => write!(f, "leveldb error: {}", e),
+            StorageError::Corrupted(msg) => write!(f, "corrupted record: {}", msg),
+            StorageError::UnknownNamespace(name) => write!(f, "no namespace {:?}", name),
+            StorageError::UnknownSnapshot(token) => write!(f, "no snapshot {}", token),
+            StorageError::TooManySnapshots => write!(f, "too many snapshots held"),
+            StorageError::NotACounter => write!(f, "value is not an integer"),
+            StorageError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl error::Error for StorageError {}
+
+impl From<rocksdb::Error> for StorageError {
+    fn from(e: rocksdb::Error) -> Self {
+        StorageError::Rocks(e)
+    }
+}
+
+impl From<rusty_leveldb::Status> for StorageError {
+    fn from(e: rusty_leveldb::Status) -> Self {
+        StorageError::LevelDb(e)
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+// so services can use `?` on storage calls and let the server answer 500
+impl From<StorageError> for io::Error {
+    fn from(e: StorageError) -> Self {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+}
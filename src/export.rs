@@ -0,0 +1,161 @@
+//! walking a whole namespace, to dump it for analysis or migration
+
+use rocksdb::{Direction, IteratorMode};
+
+use crate::error::StorageError;
+use crate::hash::{self, HASH_TAG};
+use crate::kv_util::{self, RocksKvUtil, STR_HEADER};
+use crate::list::{self, LIST_TAG};
+use crate::ttl;
+use crate::zset::{self, ZSET_TAG};
+
+/// one record `RocksKvUtil::export` visits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExportEntry {
+    Str {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// unix millis the key expires at
+        expires_at: Option<u64>,
+    },
+    /// one member of a sorted set, the members of a set come in a row
+    ZMember {
+        key: Vec<u8>,
+        member: Vec<u8>,
+        score: u32,
+        expires_at: Option<u64>,
+    },
+    /// one field of a hash, the fields of a hash come in a row
+    HField {
+        key: Vec<u8>,
+        field: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// one element of a list, the elements of a list come in a row,
+    /// head first
+    ListItem {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+}
+
+// deadlines are 0 for never
+fn expires_at(deadline: u64) -> Option<u64> {
+    Some(deadline).filter(|&d| d != 0)
+}
+
+impl RocksKvUtil {
+    /// visit the strings, then the sorted set members, the hash fields and
+    /// the list elements of the namespace whose keys start with `prefix`,
+    /// until `f` returns false
+    ///
+    /// everything is read from one snapshot, expired keys are left out
+    pub fn export(
+        &self,
+        prefix: &[u8],
+        f: &mut dyn FnMut(ExportEntry) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError> {
+        let cf = self.cf()?;
+        let snapshot = self.db.snapshot();
+
+        let from = kv_util::str_key(prefix);
+        let mode = IteratorMode::From(&from, Direction::Forward);
+        for item in snapshot.iterator_cf(&cf, mode) {
+            let (k, v) = item?;
+            if !k.starts_with(&from) {
+                break;
+            }
+            let deadline = kv_util::str_deadline(&v)?;
+            if ttl::is_expired(deadline) {
+                continue;
+            }
+            let entry = ExportEntry::Str {
+                key: k[1..].to_vec(),
+                value: v[STR_HEADER..].to_vec(),
+                expires_at: expires_at(deadline),
+            };
+            if !f(entry)? {
+                return Ok(());
+            }
+        }
+
+        // the length in front of the set key keeps prefixes from lining
+        // up in the engine's order, so every set is looked at
+        let mut set: Option<(Vec<u8>, u64)> = None;
+        let mode = IteratorMode::From(&[ZSET_TAG], Direction::Forward);
+        for item in snapshot.iterator_cf(&cf, mode) {
+            let (k, v) = item?;
+            if k.first() != Some(&ZSET_TAG) {
+                break;
+            }
+            let (key, member, score) = match zset::decode_member(&k, &v)? {
+                Some(record) if record.0.starts_with(prefix) => record,
+                _ => continue,
+            };
+            // the deadline is in the set's `'n'` record, read once per set
+            let deadline = match set.as_ref() {
+                Some((last, deadline)) if last.as_slice() == key => *deadline,
+                _ => {
+                    let deadline = match snapshot.get_cf(&cf, zset::meta_key(key))? {
+                        Some(meta) => zset::meta_deadline(&meta)?,
+                        None => 0,
+                    };
+                    set = Some((key.to_vec(), deadline));
+                    deadline
+                }
+            };
+            if ttl::is_expired(deadline) {
+                continue;
+            }
+            let entry = ExportEntry::ZMember {
+                key: key.to_vec(),
+                member: member.to_vec(),
+                score,
+                expires_at: expires_at(deadline),
+            };
+            if !f(entry)? {
+                return Ok(());
+            }
+        }
+
+        let mode = IteratorMode::From(&[HASH_TAG], Direction::Forward);
+        for item in snapshot.iterator_cf(&cf, mode) {
+            let (k, v) = item?;
+            if k.first() != Some(&HASH_TAG) {
+                break;
+            }
+            let (key, field) = match hash::decode_field(&k)? {
+                Some(record) if record.0.starts_with(prefix) => record,
+                _ => continue,
+            };
+            let entry = ExportEntry::HField {
+                key: key.to_vec(),
+                field: field.to_vec(),
+                value: v.to_vec(),
+            };
+            if !f(entry)? {
+                return Ok(());
+            }
+        }
+
+        let mode = IteratorMode::From(&[LIST_TAG], Direction::Forward);
+        for item in snapshot.iterator_cf(&cf, mode) {
+            let (k, v) = item?;
+            if k.first() != Some(&LIST_TAG) {
+                break;
+            }
+            let key = match list::decode_item(&k)? {
+                Some(key) if key.starts_with(prefix) => key,
+                _ => continue,
+            };
+            let entry = ExportEntry::ListItem {
+                key: key.to_vec(),
+                value: v.to_vec(),
+            };
+            if !f(entry)? {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
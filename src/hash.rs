@@ -0,0 +1,179 @@
+//! hashes, small objects stored field by field
+//!
+//! `h | key len | key | 'f' | field` -> value
+//!
+//! plus `h | key len | key | 'n'` -> field count, so `hlen` needn't scan.
+//! hashes don't expire
+
+use crate::error::StorageError;
+use crate::keys;
+use crate::txn::Txn;
+
+pub(crate) const HASH_TAG: u8 = b'h';
+const FIELD_TAG: u8 = b'f';
+const META_TAG: u8 = b'n';
+
+fn hash_prefix(key: &[u8], index: u8) -> Vec<u8> {
+    keys::composite_prefix(HASH_TAG, key, index)
+}
+
+fn field_key(key: &[u8], field: &[u8]) -> Vec<u8> {
+    let mut k = hash_prefix(key, FIELD_TAG);
+    k.extend_from_slice(field);
+    k
+}
+
+/// the hash key and field of a `'f'` record,
+/// `None` for the hash's other records
+pub(crate) fn decode_field(k: &[u8]) -> Result<Option<(&[u8], &[u8])>, StorageError> {
+    match keys::decode_composite(HASH_TAG, k)? {
+        Some((key, FIELD_TAG, field)) => Ok(Some((key, field))),
+        _ => Ok(None),
+    }
+}
+
+fn meta_key(key: &[u8]) -> Vec<u8> {
+    hash_prefix(key, META_TAG)
+}
+
+fn load_len(txn: &mut Txn, key: &[u8]) -> Result<u64, StorageError> {
+    match txn.get(&meta_key(key))? {
+        Some(v) if v.len() == 8 => {
+            let mut count = [0u8; 8];
+            count.copy_from_slice(&v);
+            Ok(u64::from_be_bytes(count))
+        }
+        Some(v) => Err(StorageError::Corrupted(format!(
+            "hash meta of {} bytes",
+            v.len()
+        ))),
+        None => Ok(0),
+    }
+}
+
+fn save_len(txn: &mut Txn, key: &[u8], count: u64) {
+    if count == 0 {
+        txn.delete(&meta_key(key));
+    } else {
+        txn.put(&meta_key(key), &count.to_be_bytes());
+    }
+}
+
+/// set `field` to `value`, true if the field is new
+pub(crate) fn set(
+    txn: &mut Txn,
+    key: &[u8],
+    field: &[u8],
+    value: &[u8],
+) -> Result<bool, StorageError> {
+    let added = txn.get(&field_key(key, field))?.is_none();
+    if added {
+        let count = load_len(txn, key)?;
+        save_len(txn, key, count + 1);
+    }
+    txn.put(&field_key(key, field), value);
+    Ok(added)
+}
+
+pub(crate) fn get(
+    txn: &mut Txn,
+    key: &[u8],
+    field: &[u8],
+) -> Result<Option<Vec<u8>>, StorageError> {
+    txn.get(&field_key(key, field))
+}
+
+/// every `(field, value)` pair of the hash, by field
+pub(crate) fn get_all(
+    txn: &mut Txn,
+    key: &[u8],
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StorageError> {
+    let prefix = hash_prefix(key, FIELD_TAG);
+    let mut fields = Vec::new();
+    txn.scan(&prefix, &mut |k, v| {
+        if !k.starts_with(&prefix) {
+            return Ok(false);
+        }
+        fields.push((k[prefix.len()..].to_vec(), v.to_vec()));
+        Ok(true)
+    })?;
+    Ok(fields)
+}
+
+/// drop `field`, false if it wasn't there
+pub(crate) fn delete(txn: &mut Txn, key: &[u8], field: &[u8]) -> Result<bool, StorageError> {
+    if txn.get(&field_key(key, field))?.is_none() {
+        return Ok(false);
+    }
+    txn.delete(&field_key(key, field));
+    let count = load_len(txn, key)?;
+    save_len(txn, key, count.saturating_sub(1));
+    Ok(true)
+}
+
+/// the number of fields, 0 for a missing hash
+pub(crate) fn len(txn: &mut Txn, key: &[u8]) -> Result<u64, StorageError> {
+    load_len(txn, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::txn::MemEngine;
+
+    fn fields(engine: &mut MemEngine, key: &str) -> Vec<(String, String)> {
+        engine
+            .update(|txn| get_all(txn, key.as_bytes()))
+            .unwrap()
+            .into_iter()
+            .map(|(f, v)| (String::from_utf8(f).unwrap(), String::from_utf8(v).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn set_counts_only_new_fields() {
+        let mut engine = MemEngine::default();
+        let added = engine
+            .update(|txn| {
+                Ok([
+                    set(txn, b"key", b"b", b"1")?,
+                    set(txn, b"key", b"a", b"2")?,
+                    set(txn, b"key", b"b", b"3")?,
+                ])
+            })
+            .unwrap();
+        assert_eq!(added, [true, true, false]);
+        assert_eq!(engine.update(|txn| len(txn, b"key")).unwrap(), 2);
+        assert_eq!(
+            fields(&mut engine, "key"),
+            [("a".to_owned(), "2".to_owned()), ("b".to_owned(), "3".to_owned())]
+        );
+    }
+
+    #[test]
+    fn get_all_keeps_to_its_own_hash() {
+        let mut engine = MemEngine::default();
+        engine
+            .update(|txn| {
+                set(txn, b"a", b"bc", b"1")?;
+                set(txn, b"ab", b"c", b"2")?;
+                set(txn, b"", b"abc", b"3")
+            })
+            .unwrap();
+        assert_eq!(fields(&mut engine, "a"), [("bc".to_owned(), "1".to_owned())]);
+        assert_eq!(fields(&mut engine, "ab"), [("c".to_owned(), "2".to_owned())]);
+        assert_eq!(fields(&mut engine, ""), [("abc".to_owned(), "3".to_owned())]);
+    }
+
+    #[test]
+    fn deleting_the_last_field_drops_the_hash() {
+        let mut engine = MemEngine::default();
+        engine.update(|txn| set(txn, b"key", b"f", b"v")).unwrap();
+        let deleted = engine
+            .update(|txn| Ok((delete(txn, b"key", b"missing")?, delete(txn, b"key", b"f")?)))
+            .unwrap();
+        assert_eq!(deleted, (false, true));
+        assert!(engine.0.is_empty());
+        assert_eq!(engine.update(|txn| get(txn, b"key", b"f")).unwrap(), None);
+    }
+}
@@ -0,0 +1,123 @@
+//! bulk loading through sst files
+//!
+//! instead of going through the wal and memtable one write at a time,
+//! the entries are sorted, written out as sst files and handed to RocksDB,
+//! which links the files into the tree as they are
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rocksdb::{BoundColumnFamily, IngestExternalFileOptions, SstFileWriter, WriteOptions};
+
+use crate::error::StorageError;
+use crate::kv_util::{self, RocksKvUtil, VERSION_KEY};
+
+// entries per sst file, also the most entries held in memory at once
+const ENTRIES_PER_FILE: usize = 1 << 20;
+
+// the directory the sst files are written to, inside the database's own,
+// so RocksDB can link them in instead of copying them
+const WORK_DIR: &str = "ingest.tmp";
+
+// removes the work directory with whatever is left in it,
+// however the ingest ends
+struct WorkDir(PathBuf);
+
+impl WorkDir {
+    fn create(path: PathBuf) -> Result<Self, StorageError> {
+        // one left over by a crash goes first
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+        Ok(WorkDir(path))
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            error!("removing {} failed: {}", self.0.display(), e);
+        }
+    }
+}
+
+impl RocksKvUtil {
+    /// set every `(key, value)` of `entries` in the namespace, without ttl,
+    /// and return how many values were written
+    ///
+    /// the entries are taken `ENTRIES_PER_FILE` at a time, so they are
+    /// never all in memory. each chunk is sorted into an sst file of its
+    /// own and ingested before the next is read, a key given more than
+    /// once gets the last of its values. the first `Err` of `entries` stops
+    /// the ingest, the chunks before it stay loaded
+    ///
+    /// the sst files are written into a directory of the database and
+    /// removed once they are in, or the ingest failed
+    pub fn ingest<I, E>(&self, entries: I) -> Result<u64, StorageError>
+    where
+        I: IntoIterator<Item = Result<(Vec<u8>, Vec<u8>), E>>,
+        StorageError: From<E>,
+    {
+        let cf = self.cf()?;
+        // held until the last chunk is in, so no other write is handed
+        // one of the versions of the loaded values
+        let _guard = self.write_lock.lock().unwrap();
+        let work_dir = WorkDir::create(self.db.path().join(WORK_DIR))?;
+        let mut entries = entries.into_iter();
+        let mut count = 0;
+        loop {
+            let mut chunk = Vec::new();
+            for entry in entries.by_ref().take(ENTRIES_PER_FILE) {
+                chunk.push(entry?);
+            }
+            if chunk.is_empty() {
+                return Ok(count);
+            }
+            count += self.ingest_chunk(&cf, &work_dir, chunk)?;
+        }
+    }
+
+    fn ingest_chunk(
+        &self,
+        cf: &Arc<BoundColumnFamily>,
+        work_dir: &WorkDir,
+        mut chunk: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<u64, StorageError> {
+        // a stable sort keeps equal keys in input order, so the last one wins
+        chunk.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut unique: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(chunk.len());
+        for entry in chunk {
+            match unique.last_mut() {
+                Some(last) if last.0 == entry.0 => *last = entry,
+                _ => unique.push(entry),
+            }
+        }
+
+        let last = self.read(kv_util::last_version)?;
+        let count = unique.len() as u64;
+        // the counter moves first, synced whatever the handle's durability,
+        // so a crash before the file is in only skips some versions
+        // instead of handing them out twice
+        let mut sync = WriteOptions::default();
+        sync.set_sync(true);
+        let root = self.root_cf()?;
+        self.db.put_cf_opt(&root, VERSION_KEY, (last + count).to_be_bytes(), &sync)?;
+
+        let file = work_dir.0.join(format!("ingest-{}.sst", last));
+        let mut writer = SstFileWriter::create(&self.cf_opts);
+        writer.open(&file)?;
+        for (i, (key, value)) in unique.iter().enumerate() {
+            let version = last + i as u64 + 1;
+            writer.put(kv_util::str_key(key), kv_util::encode_str(value, 0, version))?;
+        }
+        writer.finish()?;
+        // a later chunk is ingested over the earlier ones, so where
+        // they share a key the later value is the one read
+        let mut ingest_opts = IngestExternalFileOptions::default();
+        ingest_opts.set_move_files(true);
+        self.db.ingest_external_file_cf_opts(cf, &ingest_opts, vec![file])?;
+        Ok(count)
+    }
+}
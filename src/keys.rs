@@ -0,0 +1,49 @@
+//! engine keys of the composite data types
+//!
+//! every record of a sorted set (`zset.rs`), hash (`hash.rs`) or list
+//! (`list.rs`) starts with
+//!
+//! `type tag | key len | key | index`
+//!
+//! where the one byte index tells the records of a value apart. the length
+//! keeps ("a", "bc") and ("ab", "c") apart
+
+use crate::error::StorageError;
+
+/// what every record of the `tag` typed `key` starts with
+pub(crate) fn key_prefix(tag: u8, key: &[u8]) -> Vec<u8> {
+    let mut k = Vec::with_capacity(key.len() + 14);
+    k.push(tag);
+    k.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    k.extend_from_slice(key);
+    k
+}
+
+/// what the `index` records of the `tag` typed `key` start with
+pub(crate) fn composite_prefix(tag: u8, key: &[u8], index: u8) -> Vec<u8> {
+    let mut k = key_prefix(tag, key);
+    k.push(index);
+    k
+}
+
+/// the key, index and whatever follows of a `tag` typed record,
+/// `None` for a record of another type
+pub(crate) fn decode_composite(
+    tag: u8,
+    k: &[u8],
+) -> Result<Option<(&[u8], u8, &[u8])>, StorageError> {
+    if k.first() != Some(&tag) {
+        return Ok(None);
+    }
+    let corrupted = || StorageError::Corrupted(format!("composite record of {} bytes", k.len()));
+    if k.len() < 5 {
+        return Err(corrupted());
+    }
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&k[1..5]);
+    let end = 5 + u32::from_be_bytes(len) as usize;
+    if k.len() <= end {
+        return Err(corrupted());
+    }
+    Ok(Some((&k[5..end], k[end], &k[end + 1..])))
+}
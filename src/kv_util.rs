@@ -1,47 +1,1101 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use may::sync::Mutex;
+use rocksdb::{
+    BoundColumnFamily, Cache, ColumnFamilyDescriptor, DBWithThreadMode, Direction, IteratorMode,
+    MultiThreaded, Options, WriteBatch, WriteOptions,
+};
+use rusty_leveldb::LdbIterator;
+
+use crate::config::EngineConfig;
+use crate::error::StorageError;
+use crate::hash;
+use crate::list;
+use crate::merge;
+use crate::namespace::DEFAULT_NAMESPACE;
+use crate::scan::{self, ScanOpts, ScanPage};
+use crate::snapshot::{SnapshotReader, Snapshots};
+use crate::ttl;
+use crate::txn::{RawEngine, Txn};
+use crate::zset::{self, ZRange};
+
+/// what `KvUtil::cas` compares the current value of the key with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected<'a> {
+    /// the version the key is at, 0 for a key that must not exist
+    Version(u64),
+    /// the value the key holds
+    Value(&'a [u8]),
+}
+
+/// how far the writes of a handle have got when they return,
+/// see `KvUtil::with_durability`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// in memory only, lost on a crash until the engine flushes it
+    Memory,
+    /// in the write ahead log, which the os may not have written out yet
+    #[default]
+    Wal,
+    /// in the write ahead log, synced to disk
+    Sync,
+}
+
+/// one write of `KvUtil::batch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    ZAdd {
+        key: Vec<u8>,
+        member: Vec<u8>,
+        score: u32,
+        ttl: Option<Duration>,
+    },
+    ZRmv {
+        key: Vec<u8>,
+        member: Vec<u8>,
+    },
+}
+
+/// the storage interface used by the http services
+///
+/// keys, values and members are arbitrary bytes,
+/// reads of a missing key give `Ok(None)`, engine failures give `Err`.
+/// writes taking a `ttl` expire that long after, expired keys read as missing.
+/// every string write gives the key a new version, higher than any before
 pub trait KvUtil {
-    fn set(&self, key: &str, value: &str);
-    fn get(&self, key: &str) -> &str;
-    fn remove(&self, key: &str);
-    fn mget(&self, keys:  &Vec<&str>) -> Vec<&str>;
-    fn mset(&self, keys: &Vec<&str>, vals: &Vec<&str>);
-    fn zadd(&self, key: &str, vals: &str, scores: &u32);
-    fn zrange(&self, key: &str, min_score: &u32, max_score: &u32) -> &str;
-    fn zrmv(&self, key: &str, value: &str);
+    /// write the value and return its version
+    fn set(&self, key: &[u8], value: &[u8], ttl: Option<Duration>) -> Result<u64, StorageError>;
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+    /// the value along with its version
+    fn get_versioned(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>, StorageError>;
+    /// write the value only if the key is as `expected`,
+    /// giving the new version, or `None` when the key didn't match
+    fn cas(
+        &self,
+        key: &[u8],
+        expected: Expected,
+        value: &[u8],
+        ttl: Option<Duration>,
+    ) -> Result<Option<u64>, StorageError>;
+    fn remove(&self, key: &[u8]) -> Result<(), StorageError>;
+    /// the values of all `keys`, read as of one point in time
+    fn mget(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, StorageError>;
+    /// the new version of each key, in order
+    fn mset(
+        &self,
+        keys: &[&[u8]],
+        vals: &[&[u8]],
+        ttl: Option<Duration>,
+    ) -> Result<Vec<u64>, StorageError>;
+    /// a `ttl` applies to the whole set, without one the set keeps its deadline
+    fn zadd(
+        &self,
+        key: &[u8],
+        val: &[u8],
+        score: u32,
+        ttl: Option<Duration>,
+    ) -> Result<(), StorageError>;
+    /// the `(member, score)` pairs of the set that fall in `range`
+    fn zrange(&self, key: &[u8], range: &ZRange) -> Result<Vec<(Vec<u8>, u32)>, StorageError>;
+    fn zrmv(&self, key: &[u8], val: &[u8]) -> Result<(), StorageError>;
+    fn zscore(&self, key: &[u8], val: &[u8]) -> Result<Option<u32>, StorageError>;
+    fn zcard(&self, key: &[u8]) -> Result<u64, StorageError>;
+    /// the number of members scored within the bounds of `range`
+    fn zcount(&self, key: &[u8], range: &ZRange) -> Result<u64, StorageError>;
+    /// the 0 based position of the member, lowest score first
+    fn zrank(&self, key: &[u8], val: &[u8]) -> Result<Option<u64>, StorageError>;
+    /// add `delta` to the member's score and return the new score,
+    /// a missing member starts at 0 and scores saturate at the `u32` ends
+    fn zincrby(&self, key: &[u8], val: &[u8], delta: i64) -> Result<u32, StorageError>;
+    /// one page of the string keys picked by `opts`
+    fn scan(&self, opts: &ScanOpts) -> Result<ScanPage, StorageError>;
+    /// apply all of `ops` in order as one atomic write, or none of them
+    /// giving the new version of the key for every `BatchOp::Set`
+    /// and `None` for the other ops, in order
+    fn batch(&self, ops: &[BatchOp]) -> Result<Vec<Option<u64>>, StorageError>;
+    /// add `delta` to the counter at `key` and return the new count
+    ///
+    /// counters are strings holding a decimal integer, a missing or empty
+    /// value counts as 0 and counts saturate at the `i64` ends. any other
+    /// value fails with `StorageError::NotACounter` and is left as it is.
+    /// the key keeps its deadline
+    fn incr(&self, key: &[u8], delta: i64) -> Result<i64, StorageError>;
+    /// a handle on the same store whose writes are as durable as `durability`,
+    /// handles start out at `Durability::Wal`
+    fn with_durability(&self, durability: Durability) -> Self
+    where
+        Self: Sized;
+    /// `incr` by `-delta`
+    fn decr(&self, key: &[u8], delta: i64) -> Result<i64, StorageError> {
+        self.incr(key, delta.saturating_neg())
+    }
+    /// add `value` to the end of the string at `key` and return its version,
+    /// a missing key starts out empty and the key keeps its deadline
+    fn append(&self, key: &[u8], value: &[u8]) -> Result<u64, StorageError>;
+    /// set a field of the hash at `key`, true if the field is new
+    fn hset(&self, key: &[u8], field: &[u8], value: &[u8]) -> Result<bool, StorageError>;
+    fn hget(&self, key: &[u8], field: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+    /// the `(field, value)` pairs of the hash, by field
+    fn hgetall(&self, key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StorageError>;
+    /// drop a field of the hash, false if it wasn't there
+    fn hdel(&self, key: &[u8], field: &[u8]) -> Result<bool, StorageError>;
+    /// the number of fields in the hash, 0 for a missing hash
+    fn hlen(&self, key: &[u8]) -> Result<u64, StorageError>;
+    /// add `value` at the head of the list at `key` and return its length
+    fn lpush(&self, key: &[u8], value: &[u8]) -> Result<u64, StorageError>;
+    /// add `value` at the tail of the list at `key` and return its length
+    fn rpush(&self, key: &[u8], value: &[u8]) -> Result<u64, StorageError>;
+    /// take the head of the list, `None` once it is empty
+    fn lpop(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+    /// take the tail of the list, `None` once it is empty
+    fn rpop(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+    /// the elements from `start` to `stop`, both included,
+    /// negative indexes count back from the tail, -1 being the last element
+    fn lrange(&self, key: &[u8], start: i64, stop: i64) -> Result<Vec<Vec<u8>>, StorageError>;
+    /// keep only the elements `lrange` would give for `start` and `stop`
+    fn ltrim(&self, key: &[u8], start: i64, stop: i64) -> Result<(), StorageError>;
+    /// the number of elements in the list, 0 for a missing list
+    fn llen(&self, key: &[u8]) -> Result<u64, StorageError>;
 }
 
 pub struct MockKvUtil {}
 impl KvUtil for MockKvUtil {
-    fn set(&self, _key: &str, _value: &str) {
-        "set success";
+    fn set(&self, _key: &[u8], _value: &[u8], _ttl: Option<Duration>) -> Result<u64, StorageError> {
+        Ok(1)
+    }
+
+    fn get(&self, _key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(Some(b"get success".to_vec()))
+    }
+
+    fn get_versioned(&self, _key: &[u8]) -> Result<Option<(Vec<u8>, u64)>, StorageError> {
+        Ok(Some((b"get success".to_vec(), 1)))
+    }
+
+    fn cas(
+        &self,
+        _key: &[u8],
+        _expected: Expected,
+        _value: &[u8],
+        _ttl: Option<Duration>,
+    ) -> Result<Option<u64>, StorageError> {
+        Ok(Some(2))
+    }
+
+    fn remove(&self, _key: &[u8]) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn mget(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        Ok(keys.iter().map(|_| Some(b"value".to_vec())).collect())
+    }
+
+    fn mset(
+        &self,
+        keys: &[&[u8]],
+        _vals: &[&[u8]],
+        _ttl: Option<Duration>,
+    ) -> Result<Vec<u64>, StorageError> {
+        Ok(keys.iter().map(|_| 1).collect())
+    }
+
+    fn zadd(
+        &self,
+        _key: &[u8],
+        _val: &[u8],
+        _score: u32,
+        _ttl: Option<Duration>,
+    ) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn zrange(&self, _key: &[u8], _range: &ZRange) -> Result<Vec<(Vec<u8>, u32)>, StorageError> {
+        Ok(vec![(b"get success".to_vec(), 0)])
+    }
+
+    fn zrmv(&self, _key: &[u8], _val: &[u8]) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn zscore(&self, _key: &[u8], _val: &[u8]) -> Result<Option<u32>, StorageError> {
+        Ok(Some(0))
+    }
+
+    fn zcard(&self, _key: &[u8]) -> Result<u64, StorageError> {
+        Ok(1)
+    }
+
+    fn zcount(&self, _key: &[u8], _range: &ZRange) -> Result<u64, StorageError> {
+        Ok(1)
+    }
+
+    fn zrank(&self, _key: &[u8], _val: &[u8]) -> Result<Option<u64>, StorageError> {
+        Ok(Some(0))
+    }
+
+    fn zincrby(&self, _key: &[u8], _val: &[u8], _delta: i64) -> Result<u32, StorageError> {
+        Ok(0)
+    }
+
+    fn scan(&self, _opts: &ScanOpts) -> Result<ScanPage, StorageError> {
+        Ok(ScanPage::default())
+    }
+
+    fn batch(&self, ops: &[BatchOp]) -> Result<Vec<Option<u64>>, StorageError> {
+        Ok(ops
+            .iter()
+            .map(|op| match op {
+                BatchOp::Set { .. } => Some(1),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn incr(&self, _key: &[u8], delta: i64) -> Result<i64, StorageError> {
+        Ok(delta)
+    }
+
+    fn append(&self, _key: &[u8], _value: &[u8]) -> Result<u64, StorageError> {
+        Ok(1)
+    }
+
+    fn hset(&self, _key: &[u8], _field: &[u8], _value: &[u8]) -> Result<bool, StorageError> {
+        Ok(true)
+    }
+
+    fn hget(&self, _key: &[u8], _field: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(Some(b"get success".to_vec()))
+    }
+
+    fn hgetall(&self, _key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StorageError> {
+        Ok(vec![(b"field".to_vec(), b"get success".to_vec())])
     }
 
-    fn get(&self, _key: &str) -> &str {
-        "get success"
+    fn hdel(&self, _key: &[u8], _field: &[u8]) -> Result<bool, StorageError> {
+        Ok(true)
     }
 
-    fn remove(&self, _key: &str) {
-        "get success";
+    fn hlen(&self, _key: &[u8]) -> Result<u64, StorageError> {
+        Ok(1)
     }
 
-    fn mget(&self, _keys: &Vec<&str>) -> Vec<&str> {
-        let mut mock_res = Vec::new();
-        mock_res.push("value");
-        mock_res
+    fn lpush(&self, _key: &[u8], _value: &[u8]) -> Result<u64, StorageError> {
+        Ok(1)
     }
 
-    fn mset(&self, _key: &Vec<&str>, _vals: &Vec<&str>) {
-        "get success";
+    fn rpush(&self, _key: &[u8], _value: &[u8]) -> Result<u64, StorageError> {
+        Ok(1)
     }
 
-    fn zadd(&self, _key: &str, _val: &str, _score: &u32) {
-        "get success";
+    fn lpop(&self, _key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(Some(b"get success".to_vec()))
     }
 
-    fn zrange(&self, _key: &str, _min_score: &u32, _max_score: &u32) -> &str {
-        "get success"
+    fn rpop(&self, _key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(Some(b"get success".to_vec()))
     }
 
-    fn zrmv(&self, _key: &str, _val: &str) {
-        "get success";
+    fn lrange(&self, _key: &[u8], _start: i64, _stop: i64) -> Result<Vec<Vec<u8>>, StorageError> {
+        Ok(vec![b"get success".to_vec()])
     }
-}
\ No newline at end of file
+
+    fn ltrim(&self, _key: &[u8], _start: i64, _stop: i64) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn llen(&self, _key: &[u8]) -> Result<u64, StorageError> {
+        Ok(1)
+    }
+
+    fn with_durability(&self, _durability: Durability) -> Self {
+        MockKvUtil {}
+    }
+}
+
+// every key handed to the storage engine starts with a one byte type tag,
+// so a string and a sorted set (`zset.rs`) with the same user key never collide
+pub(crate) const STR_TAG: u8 = b's';
+
+pub(crate) fn str_key(key: &[u8]) -> Vec<u8> {
+    let mut k = Vec::with_capacity(key.len() + 1);
+    k.push(STR_TAG);
+    k.extend_from_slice(key);
+    k
+}
+
+// string records are `deadline | version | value`, see `ttl.rs` for the
+// deadline. versions come from one engine wide counter, so they only ever
+// grow, also across a delete and re-create of the key or its namespace
+pub(crate) const STR_HEADER: usize = 16;
+
+// internal bookkeeping records live under the `#` tag
+//
+// the version counter is read and written through the namespace's `Txn`
+// like any other key, RocksDB keeps it in the default column family
+// whatever the namespace (`is_root_key`)
+pub(crate) const VERSION_KEY: &[u8] = b"#version";
+
+// the keys kept once for the whole database rather than per namespace
+pub(crate) fn is_root_key(key: &[u8]) -> bool {
+    key == VERSION_KEY
+}
+
+pub(crate) fn encode_str(value: &[u8], deadline: u64, version: u64) -> Vec<u8> {
+    let mut v = Vec::with_capacity(value.len() + STR_HEADER);
+    v.extend_from_slice(&deadline.to_be_bytes());
+    v.extend_from_slice(&version.to_be_bytes());
+    v.extend_from_slice(value);
+    v
+}
+
+fn check_str(record: &[u8]) -> Result<(), StorageError> {
+    if record.len() < STR_HEADER {
+        return Err(StorageError::Corrupted(format!(
+            "string record of {} bytes",
+            record.len()
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn str_deadline(record: &[u8]) -> Result<u64, StorageError> {
+    check_str(record)?;
+    let mut deadline = [0u8; 8];
+    deadline.copy_from_slice(&record[..8]);
+    Ok(u64::from_be_bytes(deadline))
+}
+
+// the value and version of a string record, `None` once it expired
+fn decode_versioned(record: Option<Vec<u8>>) -> Result<Option<(Vec<u8>, u64)>, StorageError> {
+    match record {
+        Some(mut record) => {
+            if ttl::is_expired(str_deadline(&record)?) {
+                return Ok(None);
+            }
+            let mut version = [0u8; 8];
+            version.copy_from_slice(&record[8..STR_HEADER]);
+            record.drain(..STR_HEADER);
+            Ok(Some((record, u64::from_be_bytes(version))))
+        }
+        None => Ok(None),
+    }
+}
+
+// the value of a string record, `None` once it expired
+pub(crate) fn decode_str(record: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, StorageError> {
+    Ok(decode_versioned(record)?.map(|(v, _)| v))
+}
+
+// the last version handed out, 0 before the first write
+pub(crate) fn last_version(txn: &mut Txn) -> Result<u64, StorageError> {
+    match txn.get(VERSION_KEY)? {
+        Some(v) if v.len() == 8 => {
+            let mut version = [0u8; 8];
+            version.copy_from_slice(&v);
+            Ok(u64::from_be_bytes(version))
+        }
+        Some(v) => Err(StorageError::Corrupted(format!(
+            "version counter of {} bytes",
+            v.len()
+        ))),
+        None => Ok(0),
+    }
+}
+
+fn next_version(txn: &mut Txn) -> Result<u64, StorageError> {
+    let version = last_version(txn)? + 1;
+    txn.put(VERSION_KEY, &version.to_be_bytes());
+    Ok(version)
+}
+
+// write the string and return its new version
+fn set_str(
+    txn: &mut Txn,
+    key: &[u8],
+    value: &[u8],
+    ttl: Option<Duration>,
+) -> Result<u64, StorageError> {
+    let deadline = ttl::deadline(ttl);
+    let version = next_version(txn)?;
+    txn.put(&str_key(key), &encode_str(value, deadline, version));
+    ttl::track(txn, STR_TAG, key, deadline);
+    Ok(version)
+}
+
+fn cas_str(
+    txn: &mut Txn,
+    key: &[u8],
+    expected: &Expected,
+    value: &[u8],
+    ttl: Option<Duration>,
+) -> Result<Option<u64>, StorageError> {
+    let current = decode_versioned(txn.get(&str_key(key))?)?;
+    let matches = match (expected, current.as_ref()) {
+        (Expected::Version(0), None) => true,
+        (Expected::Version(want), Some((_, version))) => want == version,
+        (Expected::Value(want), Some((val, _))) => want == val,
+        _ => false,
+    };
+    if !matches {
+        return Ok(None);
+    }
+    set_str(txn, key, value, ttl).map(Some)
+}
+
+// counters are kept as decimal text so they read like any other string,
+// an empty value counts as 0 and `None` is a value that isn't a counter
+pub(crate) fn parse_counter(value: &[u8]) -> Option<i64> {
+    if value.is_empty() {
+        return Some(0);
+    }
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+// rewrite the string in place, keeping its deadline, and return the new
+// version. what the merge operator (`merge.rs`) does, for engines without one
+fn modify_str(
+    txn: &mut Txn,
+    key: &[u8],
+    f: impl FnOnce(&mut Vec<u8>) -> Result<(), StorageError>,
+) -> Result<u64, StorageError> {
+    let record = txn.get(&str_key(key))?;
+    let deadline = match record.as_ref() {
+        Some(record) => str_deadline(record)?,
+        None => 0,
+    };
+    let deadline = if ttl::is_expired(deadline) { 0 } else { deadline };
+    let mut value = decode_str(record)?.unwrap_or_default();
+    f(&mut value)?;
+    let version = next_version(txn)?;
+    txn.put(&str_key(key), &encode_str(&value, deadline, version));
+    Ok(version)
+}
+
+fn incr_str(txn: &mut Txn, key: &[u8], delta: i64) -> Result<i64, StorageError> {
+    let mut count = 0;
+    modify_str(txn, key, |value| {
+        let current = parse_counter(value).ok_or(StorageError::NotACounter)?;
+        count = current.saturating_add(delta);
+        *value = count.to_string().into_bytes();
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+/// drop the string if it is still due at `deadline`, for the expiry sweep
+pub(crate) fn expire_str(txn: &mut Txn, key: &[u8], deadline: u64) -> Result<(), StorageError> {
+    if let Some(record) = txn.get(&str_key(key))? {
+        if str_deadline(&record)? == deadline && ttl::is_expired(deadline) {
+            txn.delete(&str_key(key));
+        }
+    }
+    Ok(())
+}
+
+// the version a `BatchOp::Set` gave its key
+fn apply_op(txn: &mut Txn, op: &BatchOp) -> Result<Option<u64>, StorageError> {
+    match op {
+        BatchOp::Set { key, value, ttl } => return set_str(txn, key, value, *ttl).map(Some),
+        BatchOp::Delete { key } => txn.delete(&str_key(key)),
+        BatchOp::ZAdd {
+            key,
+            member,
+            score,
+            ttl,
+        } => zset::add(txn, key, member, *score, *ttl)?,
+        BatchOp::ZRmv { key, member } => zset::remove(txn, key, member)?,
+    }
+    Ok(None)
+}
+
+// the `KvUtil` methods that run as a `Txn` through the engine's own `read`
+// and `update`, the engines only write out the ones they do their own way
+macro_rules! txn_methods {
+    () => {
+        fn set(
+            &self,
+            key: &[u8],
+            value: &[u8],
+            ttl: Option<Duration>,
+        ) -> Result<u64, StorageError> {
+            self.update(|txn| set_str(txn, key, value, ttl))
+        }
+
+        fn cas(
+            &self,
+            key: &[u8],
+            expected: Expected,
+            value: &[u8],
+            ttl: Option<Duration>,
+        ) -> Result<Option<u64>, StorageError> {
+            self.update(|txn| cas_str(txn, key, &expected, value, ttl))
+        }
+
+        fn remove(&self, key: &[u8]) -> Result<(), StorageError> {
+            self.update(|txn| {
+                txn.delete(&str_key(key));
+                Ok(())
+            })
+        }
+
+        fn mset(
+            &self,
+            keys: &[&[u8]],
+            vals: &[&[u8]],
+            ttl: Option<Duration>,
+        ) -> Result<Vec<u64>, StorageError> {
+            self.update(|txn| {
+                keys.iter()
+                    .zip(vals.iter())
+                    .map(|(k, v)| set_str(txn, k, v, ttl))
+                    .collect()
+            })
+        }
+
+        fn zadd(
+            &self,
+            key: &[u8],
+            val: &[u8],
+            score: u32,
+            ttl: Option<Duration>,
+        ) -> Result<(), StorageError> {
+            self.update(|txn| zset::add(txn, key, val, score, ttl))
+        }
+
+        fn zrange(&self, key: &[u8], range: &ZRange) -> Result<Vec<(Vec<u8>, u32)>, StorageError> {
+            self.read(|txn| zset::range(txn, key, range))
+        }
+
+        fn zrmv(&self, key: &[u8], val: &[u8]) -> Result<(), StorageError> {
+            self.update(|txn| zset::remove(txn, key, val))
+        }
+
+        fn zscore(&self, key: &[u8], val: &[u8]) -> Result<Option<u32>, StorageError> {
+            self.read(|txn| zset::score(txn, key, val))
+        }
+
+        fn zcard(&self, key: &[u8]) -> Result<u64, StorageError> {
+            self.read(|txn| zset::card(txn, key))
+        }
+
+        fn zcount(&self, key: &[u8], range: &ZRange) -> Result<u64, StorageError> {
+            self.read(|txn| zset::count(txn, key, range))
+        }
+
+        fn zrank(&self, key: &[u8], val: &[u8]) -> Result<Option<u64>, StorageError> {
+            self.read(|txn| zset::rank(txn, key, val))
+        }
+
+        fn zincrby(&self, key: &[u8], val: &[u8], delta: i64) -> Result<u32, StorageError> {
+            self.update(|txn| zset::incr_by(txn, key, val, delta))
+        }
+
+        fn scan(&self, opts: &ScanOpts) -> Result<ScanPage, StorageError> {
+            self.read(|txn| scan::page(txn, opts))
+        }
+
+        fn batch(&self, ops: &[BatchOp]) -> Result<Vec<Option<u64>>, StorageError> {
+            self.update(|txn| ops.iter().map(|op| apply_op(txn, op)).collect())
+        }
+
+        fn hset(&self, key: &[u8], field: &[u8], value: &[u8]) -> Result<bool, StorageError> {
+            self.update(|txn| hash::set(txn, key, field, value))
+        }
+
+        fn hget(&self, key: &[u8], field: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+            self.read(|txn| hash::get(txn, key, field))
+        }
+
+        fn hgetall(&self, key: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StorageError> {
+            self.read(|txn| hash::get_all(txn, key))
+        }
+
+        fn hdel(&self, key: &[u8], field: &[u8]) -> Result<bool, StorageError> {
+            self.update(|txn| hash::delete(txn, key, field))
+        }
+
+        fn hlen(&self, key: &[u8]) -> Result<u64, StorageError> {
+            self.read(|txn| hash::len(txn, key))
+        }
+
+        fn lpush(&self, key: &[u8], value: &[u8]) -> Result<u64, StorageError> {
+            self.update(|txn| list::push(txn, key, value, false))
+        }
+
+        fn rpush(&self, key: &[u8], value: &[u8]) -> Result<u64, StorageError> {
+            self.update(|txn| list::push(txn, key, value, true))
+        }
+
+        fn lpop(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+            self.update(|txn| list::pop(txn, key, false))
+        }
+
+        fn rpop(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+            self.update(|txn| list::pop(txn, key, true))
+        }
+
+        fn lrange(&self, key: &[u8], start: i64, stop: i64) -> Result<Vec<Vec<u8>>, StorageError> {
+            self.read(|txn| list::range(txn, key, start, stop))
+        }
+
+        fn ltrim(&self, key: &[u8], start: i64, stop: i64) -> Result<(), StorageError> {
+            self.update(|txn| list::trim(txn, key, start, stop))
+        }
+
+        fn llen(&self, key: &[u8]) -> Result<u64, StorageError> {
+            self.read(|txn| list::len(txn, key))
+        }
+    };
+}
+
+// keep sweeping expired keys until `is_dropped` says the store is gone
+fn spawn_sweeper<F, D>(sweep_once: F, is_dropped: D)
+where
+    F: Fn() -> Result<usize, StorageError> + Send + 'static,
+    D: Fn() -> bool + Send + 'static,
+{
+    may::go!(move || loop {
+        may::coroutine::sleep(ttl::SWEEP_INTERVAL);
+        if is_dropped() {
+            break;
+        }
+        loop {
+            match sweep_once() {
+                Ok(n) if n == ttl::SWEEP_BATCH => continue,
+                Ok(_) => break,
+                Err(e) => {
+                    error!("expiry sweep failed: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+// column families are created and dropped while the database is shared
+pub(crate) type RocksDb = DBWithThreadMode<MultiThreaded>;
+
+/// `KvUtil` that persists into a RocksDB directory
+///
+/// cloning is cheap and all the clones share the same database,
+/// so one instance can be handed to every per connection service.
+/// a handle reads and writes one namespace (`namespace.rs`),
+/// the one `open` returns is on the default namespace
+#[derive(Clone)]
+pub struct RocksKvUtil {
+    pub(crate) db: Arc<RocksDb>,
+    // serializes the writes, which may read-modify-write
+    pub(crate) write_lock: Arc<Mutex<()>>,
+    // the name of the namespace's column family
+    pub(crate) ns: Arc<str>,
+    pub(crate) snapshots: Arc<Mutex<Snapshots>>,
+    pub(crate) durability: Durability,
+    // the options of `EngineConfig` new column families and sst files get
+    pub(crate) cf_opts: Arc<Options>,
+    // the options the database was opened with, they hold its tickers
+    pub(crate) db_opts: Arc<Options>,
+}
+
+struct RocksReader<'a> {
+    db: &'a RocksDb,
+    cf: Arc<BoundColumnFamily<'a>>,
+    // the default column family, for the root keys
+    root: Arc<BoundColumnFamily<'a>>,
+}
+
+impl<'a> RawEngine for RocksReader<'a> {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let cf = if is_root_key(key) { &self.root } else { &self.cf };
+        Ok(self.db.get_cf(cf, key)?)
+    }
+
+    fn scan(
+        &mut self,
+        from: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError> {
+        let mode = IteratorMode::From(from, Direction::Forward);
+        for item in self.db.iterator_cf(&self.cf, mode) {
+            let (k, v) = item?;
+            if !f(&k, &v)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_rev(
+        &mut self,
+        from: &[u8],
+        to: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError> {
+        let mode = IteratorMode::From(to, Direction::Reverse);
+        for item in self.db.iterator_cf(&self.cf, mode) {
+            let (k, v) = item?;
+            // the iterator starts on `to` itself when it is there
+            if &k[..] >= to {
+                continue;
+            }
+            if &k[..] < from || !f(&k, &v)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RocksKvUtil {
+    /// open the database at `path`, creating it if missing
+    ///
+    /// this also starts the coroutine reclaiming expired keys,
+    /// it stops once every clone of the returned handle is dropped
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        Self::open_with(path, &EngineConfig::default())
+    }
+
+    /// `open` with the engine tuned by `config`
+    pub fn open_with<P: AsRef<Path>>(
+        path: P,
+        config: &EngineConfig,
+    ) -> Result<Self, StorageError> {
+        let opts = config.db_options();
+        let cache = config.block_cache_size.map(Cache::new_lru_cache);
+        let cf_opts = Arc::new(config.cf_options(cache.as_ref()));
+        // every column family has to be named to open the database,
+        // a new one only has the default
+        let names = RocksDb::list_cf(&opts, &path)
+            .unwrap_or_else(|_| vec![DEFAULT_NAMESPACE.to_owned()]);
+        let cfs = names
+            .into_iter()
+            .map(|name| ColumnFamilyDescriptor::new(name, (*cf_opts).clone()));
+        let db = RocksDb::open_cf_descriptors(&opts, path, cfs)?;
+        let kv = RocksKvUtil {
+            db: Arc::new(db),
+            write_lock: Arc::new(Mutex::new(())),
+            ns: Arc::from(DEFAULT_NAMESPACE),
+            snapshots: Arc::new(Mutex::new(Snapshots::default())),
+            durability: Durability::default(),
+            cf_opts,
+            db_opts: Arc::new(opts),
+        };
+        kv.restore_namespaces()?;
+        let sweeper = kv.clone();
+        let weak = Arc::downgrade(&kv.db);
+        spawn_sweeper(
+            move || {
+                sweeper.release_expired_snapshots();
+                sweeper.sweep_namespaces()
+            },
+            move || weak.strong_count() <= 1,
+        );
+        Ok(kv)
+    }
+
+    // the column family of the namespace, gone once it was dropped
+    pub(crate) fn cf(&self) -> Result<Arc<BoundColumnFamily>, StorageError> {
+        self.db
+            .cf_handle(&self.ns)
+            .ok_or_else(|| StorageError::UnknownNamespace(self.ns.to_string()))
+    }
+
+    // the default column family, which is never dropped
+    pub(crate) fn root_cf(&self) -> Result<Arc<BoundColumnFamily>, StorageError> {
+        self.db
+            .cf_handle(DEFAULT_NAMESPACE)
+            .ok_or_else(|| StorageError::UnknownNamespace(DEFAULT_NAMESPACE.to_owned()))
+    }
+
+    pub(crate) fn write_options(&self) -> WriteOptions {
+        let mut opts = WriteOptions::default();
+        match self.durability {
+            Durability::Memory => opts.disable_wal(true),
+            Durability::Wal => {}
+            Durability::Sync => opts.set_sync(true),
+        }
+        opts
+    }
+
+    fn reader(&self) -> Result<RocksReader, StorageError> {
+        Ok(RocksReader {
+            db: &self.db,
+            cf: self.cf()?,
+            root: self.root_cf()?,
+        })
+    }
+
+    pub(crate) fn read<R>(
+        &self,
+        f: impl FnOnce(&mut Txn) -> Result<R, StorageError>,
+    ) -> Result<R, StorageError> {
+        // a write committing halfway through `f` stays out of what it reads
+        let snapshot = self.db.snapshot();
+        let mut reader = SnapshotReader {
+            snapshot: &snapshot,
+            cf: self.cf()?,
+            root: self.root_cf()?,
+        };
+        f(&mut Txn::new(&mut reader))
+    }
+
+    // run `f` under the write lock and commit what it wrote in one batch
+    pub(crate) fn update<R>(
+        &self,
+        f: impl FnOnce(&mut Txn) -> Result<R, StorageError>,
+    ) -> Result<R, StorageError> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut reader = self.reader()?;
+        let (cf, root) = (reader.cf.clone(), reader.root.clone());
+        let mut txn = Txn::new(&mut reader);
+        let ret = f(&mut txn)?;
+        let writes = txn.into_writes();
+        // one batch may span column families, so the root keys
+        // commit along with the namespace's
+        let mut batch = WriteBatch::default();
+        for (from, to) in writes.ranges {
+            batch.delete_range_cf(&cf, from, to);
+        }
+        for (k, v) in writes.keys {
+            let cf = if is_root_key(&k) { &root } else { &cf };
+            match v {
+                Some(v) => batch.put_cf(cf, k, v),
+                None => batch.delete_cf(cf, k),
+            }
+        }
+        self.db.write_opt(batch, &self.write_options())?;
+        Ok(ret)
+    }
+
+    // hand the engine the merge operand `operand` builds for the string at
+    // `key` from the key's new version and the unix millis the write happens
+    // at, then give that version to `then`. both run under the write lock,
+    // so no other write comes in between
+    fn merge_str<R>(
+        &self,
+        key: &[u8],
+        operand: impl FnOnce(u64, u64) -> Vec<u8>,
+        then: impl FnOnce(u64) -> Result<R, StorageError>,
+    ) -> Result<R, StorageError> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut reader = self.reader()?;
+        let (cf, root) = (reader.cf.clone(), reader.root.clone());
+        let version = next_version(&mut Txn::new(&mut reader))?;
+        let mut batch = WriteBatch::default();
+        batch.put_cf(&root, VERSION_KEY, version.to_be_bytes());
+        batch.merge_cf(&cf, str_key(key), operand(version, ttl::now_ms()));
+        self.db.write_opt(batch, &self.write_options())?;
+        then(version)
+    }
+}
+
+impl KvUtil for RocksKvUtil {
+    txn_methods!();
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        decode_str(self.db.get_cf(&self.cf()?, str_key(key))?)
+    }
+
+    fn get_versioned(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>, StorageError> {
+        decode_versioned(self.db.get_cf(&self.cf()?, str_key(key))?)
+    }
+
+    fn mget(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        let cf = self.cf()?;
+        // every key is read as of one snapshot, so a concurrent write
+        // is either seen for all of them or for none
+        self.db
+            .snapshot()
+            .multi_get_cf(keys.iter().map(|k| (&cf, str_key(k))))
+            .into_iter()
+            .map(|v| decode_str(v?))
+            .collect()
+    }
+
+    fn incr(&self, key: &[u8], delta: i64) -> Result<i64, StorageError> {
+        // the key is only read after the operand is in, for the count the
+        // merge (`merge.rs`) gives
+        self.merge_str(
+            key,
+            |version, now| merge::incr_operand(version, now, delta),
+            |_| {
+                let record = self
+                    .db
+                    .get_cf(&self.cf()?, str_key(key))?
+                    .ok_or_else(|| StorageError::Corrupted("counter missing".to_owned()))?;
+                check_str(&record)?;
+                parse_counter(&record[STR_HEADER..]).ok_or(StorageError::NotACounter)
+            },
+        )
+    }
+
+    fn append(&self, key: &[u8], value: &[u8]) -> Result<u64, StorageError> {
+        // only the version counter is read, never the key itself
+        self.merge_str(key, |version, now| merge::append_operand(version, now, value), Ok)
+    }
+
+    fn with_durability(&self, durability: Durability) -> Self {
+        RocksKvUtil {
+            durability,
+            ..self.clone()
+        }
+    }
+}
+
+// rusty-leveldb keeps `Rc`s inside its handle, so it is not `Send` by itself.
+// the handle is only ever reached through the mutex in `LevelDbKvUtil`,
+// which makes sure no two threads touch those `Rc`s at the same time
+struct LevelDb(rusty_leveldb::DB);
+unsafe impl Send for LevelDb {}
+
+/// `KvUtil` backed by the pure rust `rusty-leveldb` engine
+///
+/// for deployments that can't link the C++ RocksDB library,
+/// like `RocksKvUtil` the clones share the same database
+#[derive(Clone)]
+pub struct LevelDbKvUtil {
+    db: Arc<Mutex<LevelDb>>,
+    // LevelDB always writes its log, so `Memory` is the same as `Wal`
+    durability: Durability,
+}
+
+// reverse scans are left to the buffering default of `RawEngine::scan_rev`
+struct LevelDbReader<'a>(&'a mut rusty_leveldb::DB);
+
+impl<'a> RawEngine for LevelDbReader<'a> {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.0.get(key))
+    }
+
+    fn scan(
+        &mut self,
+        from: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError> {
+        let mut iter = self.0.new_iter()?;
+        iter.seek(from);
+        let (mut k, mut v) = (Vec::new(), Vec::new());
+        while iter.valid() {
+            iter.current(&mut k, &mut v);
+            if !f(&k, &v)? {
+                break;
+            }
+            iter.advance();
+        }
+        Ok(())
+    }
+}
+
+impl LevelDbKvUtil {
+    /// open the database at `path`, creating it if missing
+    ///
+    /// like `RocksKvUtil::open` this starts the expiry coroutine
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StorageError> {
+        let mut opts = rusty_leveldb::Options::default();
+        opts.create_if_missing = true;
+        let db = rusty_leveldb::DB::open(path, opts)?;
+        let kv = LevelDbKvUtil {
+            db: Arc::new(Mutex::new(LevelDb(db))),
+            durability: Durability::default(),
+        };
+        let sweeper = kv.clone();
+        let weak = Arc::downgrade(&kv.db);
+        spawn_sweeper(
+            move || sweeper.update(ttl::sweep),
+            move || weak.strong_count() <= 1,
+        );
+        Ok(kv)
+    }
+
+    fn read<R>(&self, f: impl FnOnce(&mut Txn) -> Result<R, StorageError>) -> Result<R, StorageError> {
+        let mut db = self.db.lock().unwrap();
+        let mut reader = LevelDbReader(&mut db.0);
+        f(&mut Txn::new(&mut reader))
+    }
+
+    // the db mutex is held for the whole of `f` and the commit
+    fn update<R>(
+        &self,
+        f: impl FnOnce(&mut Txn) -> Result<R, StorageError>,
+    ) -> Result<R, StorageError> {
+        let mut db = self.db.lock().unwrap();
+        let (ret, writes) = {
+            let mut reader = LevelDbReader(&mut db.0);
+            let mut txn = Txn::new(&mut reader);
+            let ret = f(&mut txn)?;
+            (ret, txn.into_writes())
+        };
+        let mut batch = rusty_leveldb::WriteBatch::new();
+        // leveldb has no range delete, so the keys are dropped one by one
+        for (from, to) in &writes.ranges {
+            let mut iter = db.0.new_iter()?;
+            iter.seek(from);
+            let (mut k, mut v) = (Vec::new(), Vec::new());
+            while iter.valid() {
+                iter.current(&mut k, &mut v);
+                if k >= *to {
+                    break;
+                }
+                batch.delete(&k);
+                iter.advance();
+            }
+        }
+        for (k, v) in writes.keys.iter() {
+            match v {
+                Some(v) => batch.put(k, v),
+                None => batch.delete(k),
+            }
+        }
+        db.0.write(batch, self.durability == Durability::Sync)?;
+        Ok(ret)
+    }
+}
+
+impl KvUtil for LevelDbKvUtil {
+    txn_methods!();
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.read(|txn| decode_str(txn.get(&str_key(key))?))
+    }
+
+    fn get_versioned(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>, StorageError> {
+        self.read(|txn| decode_versioned(txn.get(&str_key(key))?))
+    }
+
+    fn mget(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        self.read(|txn| {
+            keys.iter()
+                .map(|k| decode_str(txn.get(&str_key(k))?))
+                .collect()
+        })
+    }
+
+    fn incr(&self, key: &[u8], delta: i64) -> Result<i64, StorageError> {
+        self.update(|txn| incr_str(txn, key, delta))
+    }
+
+    fn append(&self, key: &[u8], value: &[u8]) -> Result<u64, StorageError> {
+        self.update(|txn| {
+            modify_str(txn, key, |v| {
+                v.extend_from_slice(value);
+                Ok(())
+            })
+        })
+    }
+
+    fn with_durability(&self, durability: Durability) -> Self {
+        LevelDbKvUtil {
+            durability,
+            ..self.clone()
+        }
+    }
+}
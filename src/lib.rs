@@ -1,15 +1,42 @@
 #[macro_use]
 extern crate log;
 
+mod backup;
+mod checkpoint;
+mod config;
 mod date;
+mod error;
+mod export;
+mod hash;
 mod http_server;
+mod ingest;
 mod request;
 mod response;
+mod keys;
 mod kv_util;
+mod list;
+mod maintenance;
+mod merge;
+mod namespace;
+mod scan;
 mod service;
+mod snapshot;
+mod stats;
+mod ttl;
+mod txn;
+mod zset;
 
+pub use backup::{BackupInfo, BackupPolicy, Backups};
+pub use config::{Compression, EngineConfig};
+pub use error::StorageError;
+pub use export::ExportEntry;
 pub use http_server::{HttpServer, HttpService, HttpServiceFactory};
 pub use request::Request;
 pub use response::{BodyWriter, Response};
-pub use kv_util::{KvUtil, MockKvUtil};
-pub use service::HiRustRocksService;
\ No newline at end of file
+pub use namespace::NamespaceOpts;
+pub use scan::{ScanOpts, ScanPage};
+pub use kv_util::{BatchOp, Durability, Expected, KvUtil, LevelDbKvUtil, MockKvUtil, RocksKvUtil};
+pub use service::HiRustRocksService;
+pub use snapshot::{MAX_SNAPSHOTS, MAX_SNAPSHOT_TTL};
+pub use stats::EngineStats;
+pub use zset::ZRange;
\ No newline at end of file
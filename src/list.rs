@@ -0,0 +1,299 @@
+//! lists, pushed and popped at either end
+//!
+//! `l | key len | key | 'i' | position` -> element
+//!
+//! plus `l | key len | key | 'n'` -> head and tail position. elements sit
+//! at head .. tail, big endian so the engine's byte order is list order.
+//! a new list starts in the middle of the `u64` range, leaving room to
+//! grow both ways. lists don't expire
+
+use crate::error::StorageError;
+use crate::keys;
+use crate::txn::Txn;
+
+pub(crate) const LIST_TAG: u8 = b'l';
+const ITEM_TAG: u8 = b'i';
+const META_TAG: u8 = b'n';
+
+const MIDDLE: u64 = 1 << 63;
+
+fn list_prefix(key: &[u8], index: u8) -> Vec<u8> {
+    keys::composite_prefix(LIST_TAG, key, index)
+}
+
+fn item_key(key: &[u8], pos: u64) -> Vec<u8> {
+    let mut k = list_prefix(key, ITEM_TAG);
+    k.extend_from_slice(&pos.to_be_bytes());
+    k
+}
+
+/// the list key of an element record, `None` for the list's other records
+pub(crate) fn decode_item(k: &[u8]) -> Result<Option<&[u8]>, StorageError> {
+    match keys::decode_composite(LIST_TAG, k)? {
+        Some((key, ITEM_TAG, _)) => Ok(Some(key)),
+        _ => Ok(None),
+    }
+}
+
+fn meta_key(key: &[u8]) -> Vec<u8> {
+    list_prefix(key, META_TAG)
+}
+
+// the `'n'` record, head included and tail excluded
+struct Meta {
+    head: u64,
+    tail: u64,
+}
+
+impl Meta {
+    fn len(&self) -> u64 {
+        self.tail - self.head
+    }
+}
+
+fn load_meta(txn: &mut Txn, key: &[u8]) -> Result<Meta, StorageError> {
+    let v = match txn.get(&meta_key(key))? {
+        Some(v) => v,
+        None => return Ok(Meta { head: MIDDLE, tail: MIDDLE }),
+    };
+    if v.len() != 16 {
+        return Err(StorageError::Corrupted(format!(
+            "list meta of {} bytes",
+            v.len()
+        )));
+    }
+    let mut head = [0u8; 8];
+    let mut tail = [0u8; 8];
+    head.copy_from_slice(&v[..8]);
+    tail.copy_from_slice(&v[8..]);
+    let meta = Meta {
+        head: u64::from_be_bytes(head),
+        tail: u64::from_be_bytes(tail),
+    };
+    if meta.head > meta.tail {
+        return Err(StorageError::Corrupted("list head past its tail".to_owned()));
+    }
+    Ok(meta)
+}
+
+fn save_meta(txn: &mut Txn, key: &[u8], meta: &Meta) {
+    if meta.len() == 0 {
+        txn.delete(&meta_key(key));
+    } else {
+        let mut v = [0u8; 16];
+        v[..8].copy_from_slice(&meta.head.to_be_bytes());
+        v[8..].copy_from_slice(&meta.tail.to_be_bytes());
+        txn.put(&meta_key(key), &v);
+    }
+}
+
+fn full() -> StorageError {
+    StorageError::Corrupted("list out of positions".to_owned())
+}
+
+/// add `value` in front of the head, or after the tail for `back`,
+/// and return the new length
+pub(crate) fn push(
+    txn: &mut Txn,
+    key: &[u8],
+    value: &[u8],
+    back: bool,
+) -> Result<u64, StorageError> {
+    let mut meta = load_meta(txn, key)?;
+    if back {
+        txn.put(&item_key(key, meta.tail), value);
+        meta.tail = meta.tail.checked_add(1).ok_or_else(full)?;
+    } else {
+        meta.head = meta.head.checked_sub(1).ok_or_else(full)?;
+        txn.put(&item_key(key, meta.head), value);
+    }
+    save_meta(txn, key, &meta);
+    Ok(meta.len())
+}
+
+/// take the head, or the tail for `back`, `None` for an empty list
+pub(crate) fn pop(txn: &mut Txn, key: &[u8], back: bool) -> Result<Option<Vec<u8>>, StorageError> {
+    let mut meta = load_meta(txn, key)?;
+    if meta.len() == 0 {
+        return Ok(None);
+    }
+    let pos = if back { meta.tail - 1 } else { meta.head };
+    let value = txn
+        .get(&item_key(key, pos))?
+        .ok_or_else(|| StorageError::Corrupted("list element missing".to_owned()))?;
+    txn.delete(&item_key(key, pos));
+    if back {
+        meta.tail -= 1;
+    } else {
+        meta.head += 1;
+    }
+    save_meta(txn, key, &meta);
+    Ok(Some(value))
+}
+
+// the positions `start ..= stop` pick, counted from the head and from
+// the tail when negative, `None` when they pick nothing
+fn positions(meta: &Meta, start: i64, stop: i64) -> Option<(u64, u64)> {
+    let len = meta.len() as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((meta.head + start as u64, meta.head + stop as u64 + 1))
+}
+
+/// the elements from `start` to `stop`, both included,
+/// negative indexes count back from the tail, -1 being the last element
+pub(crate) fn range(
+    txn: &mut Txn,
+    key: &[u8],
+    start: i64,
+    stop: i64,
+) -> Result<Vec<Vec<u8>>, StorageError> {
+    let meta = load_meta(txn, key)?;
+    let (from, to) = match positions(&meta, start, stop) {
+        Some(picked) => picked,
+        None => return Ok(Vec::new()),
+    };
+    let prefix = list_prefix(key, ITEM_TAG);
+    let end = item_key(key, to);
+    let mut items = Vec::new();
+    txn.scan(&item_key(key, from), &mut |k, v| {
+        if !k.starts_with(&prefix) || k >= &end[..] {
+            return Ok(false);
+        }
+        items.push(v.to_vec());
+        Ok(true)
+    })?;
+    Ok(items)
+}
+
+/// keep only the elements from `start` to `stop`, indexed like `range`
+pub(crate) fn trim(txn: &mut Txn, key: &[u8], start: i64, stop: i64) -> Result<(), StorageError> {
+    let mut meta = load_meta(txn, key)?;
+    let (from, to) = positions(&meta, start, stop).unwrap_or((meta.tail, meta.tail));
+    // two range deletes however many elements go
+    txn.delete_range(&item_key(key, meta.head), &item_key(key, from));
+    txn.delete_range(&item_key(key, to), &item_key(key, meta.tail));
+    meta.head = from;
+    meta.tail = to;
+    save_meta(txn, key, &meta);
+    Ok(())
+}
+
+/// the number of elements, 0 for a missing list
+pub(crate) fn len(txn: &mut Txn, key: &[u8]) -> Result<u64, StorageError> {
+    Ok(load_meta(txn, key)?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::txn::MemEngine;
+
+    fn meta(len: u64) -> Meta {
+        Meta {
+            head: MIDDLE,
+            tail: MIDDLE + len,
+        }
+    }
+
+    // a list `key` holding `a`, `b`, `c`, `d`, `e`
+    fn abcde() -> MemEngine {
+        let mut engine = MemEngine::default();
+        engine
+            .update(|txn| {
+                for v in ["a", "b", "c", "d", "e"].iter() {
+                    push(txn, b"key", v.as_bytes(), true)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        engine
+    }
+
+    fn range_of(engine: &mut MemEngine, start: i64, stop: i64) -> Vec<String> {
+        engine
+            .update(|txn| range(txn, b"key", start, stop))
+            .unwrap()
+            .into_iter()
+            .map(|v| String::from_utf8(v).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn positions_count_back_from_the_tail() {
+        let m = meta(5);
+        assert_eq!(positions(&m, 0, -1), Some((MIDDLE, MIDDLE + 5)));
+        assert_eq!(positions(&m, -2, -1), Some((MIDDLE + 3, MIDDLE + 5)));
+        assert_eq!(positions(&m, 1, -2), Some((MIDDLE + 1, MIDDLE + 4)));
+        assert_eq!(positions(&m, -1, -2), None);
+    }
+
+    #[test]
+    fn positions_clamp_out_of_range_indexes() {
+        let m = meta(5);
+        assert_eq!(positions(&m, -100, 100), Some((MIDDLE, MIDDLE + 5)));
+        assert_eq!(positions(&m, 3, 100), Some((MIDDLE + 3, MIDDLE + 5)));
+        assert_eq!(positions(&m, 5, 10), None);
+        assert_eq!(positions(&m, 0, -6), None);
+        assert_eq!(positions(&m, i64::MIN, i64::MAX), Some((MIDDLE, MIDDLE + 5)));
+        assert_eq!(positions(&meta(0), 0, -1), None);
+        assert_eq!(positions(&meta(0), -1, 0), None);
+    }
+
+    #[test]
+    fn range_picks_the_indexed_elements() {
+        let mut engine = abcde();
+        assert_eq!(range_of(&mut engine, 0, -1), ["a", "b", "c", "d", "e"]);
+        assert_eq!(range_of(&mut engine, -3, 2), ["c"]);
+        assert_eq!(range_of(&mut engine, 3, 100), ["d", "e"]);
+        assert!(range_of(&mut engine, 5, -1).is_empty());
+    }
+
+    #[test]
+    fn pushes_and_pops_at_both_ends() {
+        let mut engine = MemEngine::default();
+        engine
+            .update(|txn| {
+                push(txn, b"key", b"b", true)?;
+                push(txn, b"key", b"a", false)?;
+                assert_eq!(push(txn, b"key", b"c", true)?, 3);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(range_of(&mut engine, 0, -1), ["a", "b", "c"]);
+        let popped = engine
+            .update(|txn| Ok((pop(txn, b"key", false)?, pop(txn, b"key", true)?)))
+            .unwrap();
+        assert_eq!(popped, (Some(b"a".to_vec()), Some(b"c".to_vec())));
+        assert_eq!(engine.update(|txn| len(txn, b"key")).unwrap(), 1);
+    }
+
+    #[test]
+    fn trim_drops_both_ends() {
+        let mut engine = abcde();
+        engine.update(|txn| trim(txn, b"key", 1, -2)).unwrap();
+        assert_eq!(range_of(&mut engine, 0, -1), ["b", "c", "d"]);
+        // the element records of `a` and `e` went with them
+        assert_eq!(engine.0.len(), 4);
+        engine
+            .update(|txn| push(txn, b"key", b"f", false).map(drop))
+            .unwrap();
+        assert_eq!(range_of(&mut engine, 0, -1), ["f", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn trim_to_nothing_empties_the_list() {
+        let mut engine = abcde();
+        engine.update(|txn| trim(txn, b"key", 3, 1)).unwrap();
+        assert_eq!(engine.update(|txn| len(txn, b"key")).unwrap(), 0);
+        assert!(engine.0.is_empty());
+
+        let mut engine = abcde();
+        engine.update(|txn| trim(txn, b"key", 10, 20)).unwrap();
+        assert!(engine.0.is_empty());
+        assert_eq!(engine.update(|txn| pop(txn, b"key", true)).unwrap(), None);
+    }
+}
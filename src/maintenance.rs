@@ -0,0 +1,64 @@
+//! operator controls over the RocksDB engine
+//!
+//! the key ranges here are ranges of string keys, `start` included and
+//! `end` excluded, either left out to be open ended
+
+use rocksdb::WriteBatch;
+
+use crate::error::StorageError;
+use crate::kv_util::{self, RocksKvUtil, STR_TAG};
+
+// the engine keys of the strings from `start` up to `end`
+fn str_range(start: Option<&[u8]>, end: Option<&[u8]>) -> (Vec<u8>, Vec<u8>) {
+    let from = start.map_or_else(|| vec![STR_TAG], kv_util::str_key);
+    let to = end.map_or_else(|| vec![STR_TAG + 1], kv_util::str_key);
+    (from, to)
+}
+
+impl RocksKvUtil {
+    /// compact the strings of the namespace from `start` up to `end` now,
+    /// dropping what was overwritten or deleted in there
+    ///
+    /// with no bounds at all the whole namespace is compacted,
+    /// sorted sets and the expiry index included
+    pub fn compact_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<(), StorageError> {
+        let cf = self.cf()?;
+        if start.is_none() && end.is_none() {
+            self.db.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+        } else {
+            let (from, to) = str_range(start, end);
+            self.db.compact_range_cf(&cf, Some(from), Some(to));
+        }
+        Ok(())
+    }
+
+    /// write the memtables of the namespace out to sst files
+    pub fn flush(&self) -> Result<(), StorageError> {
+        Ok(self.db.flush_cf(&self.cf()?)?)
+    }
+
+    /// delete every string of the namespace from `start` up to `end`
+    ///
+    /// this writes one range tombstone however many keys are in the
+    /// range, the space comes back as compactions reach it. snapshots
+    /// taken before still see the keys
+    pub fn delete_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<(), StorageError> {
+        let cf = self.cf()?;
+        let (from, to) = str_range(start, end);
+        // the expiry index may still name deleted keys, the sweep finds
+        // them gone and just drops the entries
+        let _guard = self.write_lock.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        batch.delete_range_cf(&cf, from, to);
+        self.db.write_opt(batch, &self.write_options())?;
+        Ok(())
+    }
+}
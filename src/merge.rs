@@ -0,0 +1,168 @@
+//! the RocksDB merge operator for string records
+//!
+//! a merge operand is `kind | version | written at | payload`, the full
+//! merge folds the operands onto the existing `deadline | version | value`
+//! record, so the engine changes a value without the key being read first.
+//! the record keeps its deadline and takes the version of the last operand
+//! applied, an incr onto a value that is not a counter is skipped
+//!
+//! RocksDB may run a merge at any time, on a read or in a compaction, and
+//! every run has to give the same record. so whether the record had expired
+//! is decided by the time each operand was written, never the time now
+
+use rocksdb::MergeOperands;
+
+use crate::kv_util::{self, STR_HEADER};
+use crate::ttl;
+
+/// the name the operator is registered under, fixed once data is written
+pub(crate) const MERGE_OPERATOR: &str = "hi_rust_rocks.str";
+
+// adds the `i64` payload to the counter in the value
+const INCR: u8 = b'i';
+// adds the payload to the end of the value
+const APPEND: u8 = b'a';
+
+const OPERAND_HEADER: usize = 17;
+
+fn operand(kind: u8, version: u64, written_at: u64, payload: &[u8]) -> Vec<u8> {
+    let mut op = Vec::with_capacity(payload.len() + OPERAND_HEADER);
+    op.push(kind);
+    op.extend_from_slice(&version.to_be_bytes());
+    op.extend_from_slice(&written_at.to_be_bytes());
+    op.extend_from_slice(payload);
+    op
+}
+
+/// `written_at` is the unix millis of the write, see `ttl::now_ms`
+pub(crate) fn incr_operand(version: u64, written_at: u64, delta: i64) -> Vec<u8> {
+    operand(INCR, version, written_at, &delta.to_be_bytes())
+}
+
+pub(crate) fn append_operand(version: u64, written_at: u64, value: &[u8]) -> Vec<u8> {
+    operand(APPEND, version, written_at, value)
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    let mut v = [0u8; 8];
+    v.copy_from_slice(bytes);
+    u64::from_be_bytes(v)
+}
+
+// fold `operands` onto the `existing` record, `None` if one is corrupted
+fn fold<'a>(
+    existing: Option<&[u8]>,
+    operands: impl IntoIterator<Item = &'a [u8]>,
+) -> Option<Vec<u8>> {
+    let (mut deadline, mut version, mut value) = match existing {
+        Some(record) => (
+            kv_util::str_deadline(record).ok()?,
+            be_u64(&record[8..STR_HEADER]),
+            record[STR_HEADER..].to_vec(),
+        ),
+        None => (0, 0, Vec::new()),
+    };
+    for op in operands {
+        if op.len() < OPERAND_HEADER {
+            return None;
+        }
+        // a record expired by the time of the write is written like a missing one
+        if ttl::is_expired_at(deadline, be_u64(&op[9..17])) {
+            deadline = 0;
+            value.clear();
+        }
+        let payload = &op[OPERAND_HEADER..];
+        match op[0] {
+            INCR if payload.len() == 8 => match kv_util::parse_counter(&value) {
+                Some(count) => {
+                    let count = count.saturating_add(be_u64(payload) as i64);
+                    value = count.to_string().into_bytes();
+                }
+                // the record is left as it is, and `KvUtil::incr`
+                // reading it back fails with `NotACounter`
+                None => continue,
+            },
+            APPEND => value.extend_from_slice(payload),
+            _ => return None,
+        }
+        version = be_u64(&op[1..9]);
+    }
+    Some(kv_util::encode_str(&value, deadline, version))
+}
+
+/// the full merge, `None` for a corrupted record or operand fails the read
+pub(crate) fn full_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    fold(existing, operands)
+}
+
+/// operands can't be combined without the record they apply to,
+/// so partial merges are left to the full merge
+pub(crate) fn partial_merge(
+    _key: &[u8],
+    _existing: Option<&[u8]>,
+    _operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Option<Vec<u8>> {
+        fold(existing, operands.iter().map(|op| op.as_slice()))
+    }
+
+    #[test]
+    fn append_before_the_deadline_keeps_it() {
+        let record = kv_util::encode_str(b"log:", 1_000, 1);
+        let merged = merge(Some(&record), &[append_operand(2, 999, b"a")]);
+        assert_eq!(merged, Some(kv_util::encode_str(b"log:a", 1_000, 2)));
+    }
+
+    #[test]
+    fn append_after_the_deadline_starts_over() {
+        let record = kv_util::encode_str(b"log:", 1_000, 1);
+        let merged = merge(Some(&record), &[append_operand(2, 1_000, b"a")]);
+        assert_eq!(merged, Some(kv_util::encode_str(b"a", 0, 2)));
+    }
+
+    #[test]
+    fn appends_straddling_the_deadline() {
+        let record = kv_util::encode_str(b"", 1_000, 1);
+        let ops = [append_operand(2, 500, b"a"), append_operand(3, 1_500, b"b")];
+        assert_eq!(merge(Some(&record), &ops), Some(kv_util::encode_str(b"b", 0, 3)));
+    }
+
+    #[test]
+    fn incr_before_the_deadline_keeps_it() {
+        let record = kv_util::encode_str(b"41", 1_000, 1);
+        let merged = merge(Some(&record), &[incr_operand(2, 999, 1)]);
+        assert_eq!(merged, Some(kv_util::encode_str(b"42", 1_000, 2)));
+    }
+
+    #[test]
+    fn incr_leaves_a_non_counter_alone() {
+        let record = kv_util::encode_str(b"abc", 0, 1);
+        let ops = [incr_operand(2, 0, 1), append_operand(3, 0, b"d")];
+        assert_eq!(merge(Some(&record), &ops), Some(kv_util::encode_str(b"abcd", 0, 3)));
+        let merged = merge(Some(&record), &[incr_operand(2, 0, 1)]);
+        assert_eq!(merged, Some(record));
+    }
+
+    #[test]
+    fn missing_record() {
+        let ops = [incr_operand(1, 0, -3), append_operand(2, 0, b"0")];
+        assert_eq!(merge(None, &ops), Some(kv_util::encode_str(b"-30", 0, 2)));
+    }
+
+    #[test]
+    fn corrupted_operand() {
+        assert_eq!(merge(None, &[vec![INCR, 0, 1]]), None);
+        assert_eq!(merge(None, &[operand(b'?', 1, 0, b"")]), None);
+    }
+}
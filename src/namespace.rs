@@ -0,0 +1,194 @@
+//! namespaces, separate keyspaces sharing one RocksDB database
+//!
+//! every namespace is a column family of its own, so the same key in two
+//! namespaces never collides and each namespace is tuned on its own.
+//! RocksDB doesn't keep that tuning across opens, so it is also stored in
+//! the default column family
+//!
+//! `#ns/ | name` -> `option=value;option=value`
+//!
+//! and applied again whenever the database opens
+
+use std::sync::Arc;
+
+use crate::error::StorageError;
+use crate::kv_util::RocksKvUtil;
+use crate::ttl;
+
+/// the namespace of the handle `RocksKvUtil::open` returns
+pub(crate) const DEFAULT_NAMESPACE: &str = rocksdb::DEFAULT_COLUMN_FAMILY_NAME;
+
+const NS_PREFIX: &[u8] = b"#ns/";
+
+/// the tuning of one namespace, `None` keeps the RocksDB default
+///
+/// these are column family options RocksDB can change on an open database
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct NamespaceOpts {
+    /// bytes written to a memtable before it is flushed
+    pub write_buffer_size: Option<u64>,
+    /// memtables kept in memory, the ones waiting for a flush included
+    pub max_write_buffer_number: Option<u32>,
+    /// size of the sst files compactions write
+    pub target_file_size_base: Option<u64>,
+    /// number of level 0 files that starts a compaction
+    pub level0_file_num_compaction_trigger: Option<u32>,
+    pub disable_auto_compactions: Option<bool>,
+}
+
+impl NamespaceOpts {
+    // the options as RocksDB `SetOptions` pairs
+    fn pairs(&self) -> Vec<(String, String)> {
+        let options = [
+            ("write_buffer_size", self.write_buffer_size.map(|v| v.to_string())),
+            ("max_write_buffer_number", self.max_write_buffer_number.map(|v| v.to_string())),
+            ("target_file_size_base", self.target_file_size_base.map(|v| v.to_string())),
+            (
+                "level0_file_num_compaction_trigger",
+                self.level0_file_num_compaction_trigger.map(|v| v.to_string()),
+            ),
+            ("disable_auto_compactions", self.disable_auto_compactions.map(|v| v.to_string())),
+        ];
+        options
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.clone()?)))
+            .collect()
+    }
+}
+
+fn ns_key(name: &str) -> Vec<u8> {
+    [NS_PREFIX, name.as_bytes()].concat()
+}
+
+fn encode_pairs(pairs: &[(String, String)]) -> Vec<u8> {
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(";")
+        .into_bytes()
+}
+
+fn decode_pairs(record: &[u8]) -> Result<Vec<(String, String)>, StorageError> {
+    let record = std::str::from_utf8(record)
+        .map_err(|_| StorageError::Corrupted("namespace options not utf-8".to_owned()))?;
+    record
+        .split(';')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((k, v)) => Ok((k.to_owned(), v.to_owned())),
+            None => Err(StorageError::Corrupted(format!(
+                "namespace option {:?}",
+                pair
+            ))),
+        })
+        .collect()
+}
+
+impl RocksKvUtil {
+    /// a handle on the namespace `name`, `None` if there is none
+    ///
+    /// like a clone the handle shares the database with `self`
+    pub fn namespace(&self, name: &str) -> Option<RocksKvUtil> {
+        self.db.cf_handle(name)?;
+        Some(RocksKvUtil {
+            ns: Arc::from(name),
+            ..self.clone()
+        })
+    }
+
+    /// the names of the namespaces made by `create_namespace`
+    pub fn namespaces(&self) -> Result<Vec<String>, StorageError> {
+        Ok(self
+            .namespace_records()?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
+
+    /// add the namespace `name`, tuned by `opts`
+    pub fn create_namespace(&self, name: &str, opts: &NamespaceOpts) -> Result<(), StorageError> {
+        self.db.create_cf(name, &self.cf_opts)?;
+        let pairs = opts.pairs();
+        self.tune(name, &pairs)?;
+        self.root().update(|txn| {
+            txn.put(&ns_key(name), &encode_pairs(&pairs));
+            Ok(())
+        })
+    }
+
+    /// drop the namespace `name` with all of its keys
+    ///
+    /// handles still on it fail with `StorageError::UnknownNamespace`
+    pub fn drop_namespace(&self, name: &str) -> Result<(), StorageError> {
+        if name == DEFAULT_NAMESPACE || self.db.cf_handle(name).is_none() {
+            return Err(StorageError::UnknownNamespace(name.to_owned()));
+        }
+        self.db.drop_cf(name)?;
+        self.root().update(|txn| {
+            txn.delete(&ns_key(name));
+            Ok(())
+        })
+    }
+
+    // the bookkeeping lives in the default namespace
+    fn root(&self) -> RocksKvUtil {
+        RocksKvUtil {
+            ns: Arc::from(DEFAULT_NAMESPACE),
+            ..self.clone()
+        }
+    }
+
+    fn namespace_records(&self) -> Result<Vec<(String, Vec<(String, String)>)>, StorageError> {
+        let mut records = Vec::new();
+        self.root().read(|txn| {
+            txn.scan(NS_PREFIX, &mut |k, v| {
+                if !k.starts_with(NS_PREFIX) {
+                    return Ok(false);
+                }
+                let name = String::from_utf8_lossy(&k[NS_PREFIX.len()..]).into_owned();
+                records.push((name, decode_pairs(v)?));
+                Ok(true)
+            })
+        })?;
+        Ok(records)
+    }
+
+    fn tune(&self, name: &str, pairs: &[(String, String)]) -> Result<(), StorageError> {
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        let cf = self
+            .db
+            .cf_handle(name)
+            .ok_or_else(|| StorageError::UnknownNamespace(name.to_owned()))?;
+        let pairs: Vec<(&str, &str)> = pairs
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        Ok(self.db.set_options_cf(&cf, &pairs)?)
+    }
+
+    /// apply the stored tuning of every namespace, after opening
+    pub(crate) fn restore_namespaces(&self) -> Result<(), StorageError> {
+        for (name, pairs) in self.namespace_records()? {
+            self.tune(&name, &pairs)?;
+        }
+        Ok(())
+    }
+
+    /// one expiry sweep of every namespace,
+    /// giving the most index entries any of them looked at
+    pub(crate) fn sweep_namespaces(&self) -> Result<usize, StorageError> {
+        let root = self.root();
+        let mut most = root.update(ttl::sweep)?;
+        for name in root.namespaces()? {
+            if let Some(ns) = root.namespace(&name) {
+                most = most.max(ns.update(ttl::sweep)?);
+            }
+        }
+        Ok(most)
+    }
+}
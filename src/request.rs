@@ -41,6 +41,13 @@ impl Request {
         }
     }
 
+    /// the value of the first header named `name`, compared case insensitively
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
     pub fn body_(&self) -> &[u8] {
         self.body.as_ref()
     }
@@ -0,0 +1,180 @@
+//! paged scans over the string keys
+
+use crate::error::StorageError;
+use crate::kv_util::{self, STR_TAG};
+use crate::txn::Txn;
+
+/// which string keys `KvUtil::scan` walks over
+///
+/// the bounds combine, a key has to be within all of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanOpts {
+    /// first key to look at, inclusive
+    pub start: Option<Vec<u8>>,
+    /// key to stop at, exclusive
+    pub end: Option<Vec<u8>>,
+    pub prefix: Option<Vec<u8>>,
+    /// glob the keys must match, `*` is any run of bytes and `?` any one byte
+    pub pattern: Option<Vec<u8>>,
+    /// at most this many entries per page
+    pub limit: usize,
+    /// the `ScanPage::cursor` of the previous page
+    pub cursor: Option<Vec<u8>>,
+}
+
+impl Default for ScanOpts {
+    fn default() -> Self {
+        ScanOpts {
+            start: None,
+            end: None,
+            prefix: None,
+            pattern: None,
+            limit: 100,
+            cursor: None,
+        }
+    }
+}
+
+/// one page of a scan, in key order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanPage {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// where the next page starts, `None` after the last page
+    pub cursor: Option<Vec<u8>>,
+}
+
+/// does `s` match the glob `pattern`
+pub(crate) fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // the last `*` seen and where in `s` it is currently matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while i < s.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, i));
+            p += 1;
+        } else if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if let Some((sp, si)) = star {
+            // let the `*` swallow one more byte and retry
+            star = Some((sp, si + 1));
+            p = sp + 1;
+            i = si + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// one page of the string keys selected by `opts`
+pub(crate) fn page(txn: &mut Txn, opts: &ScanOpts) -> Result<ScanPage, StorageError> {
+    // start at the highest of the lower bounds
+    let mut lower = opts.start.clone().unwrap_or_default();
+    if let Some(prefix) = opts.prefix.as_ref() {
+        if *prefix > lower {
+            lower = prefix.clone();
+        }
+    }
+    if let Some(cursor) = opts.cursor.as_ref() {
+        // the smallest key after the cursor
+        let mut after = cursor.clone();
+        after.push(0);
+        if after > lower {
+            lower = after;
+        }
+    }
+    let mut from = vec![STR_TAG];
+    from.extend_from_slice(&lower);
+
+    let limit = opts.limit.max(1);
+    let mut page = ScanPage::default();
+    let mut more = false;
+    txn.scan(&from, &mut |k, v| {
+        if k.first() != Some(&STR_TAG) {
+            return Ok(false);
+        }
+        let key = &k[1..];
+        if opts.end.as_ref().is_some_and(|end| key >= &end[..])
+            || opts.prefix.as_ref().is_some_and(|p| !key.starts_with(p))
+        {
+            return Ok(false);
+        }
+        if opts.pattern.as_ref().is_some_and(|p| !glob_match(p, key)) {
+            return Ok(true);
+        }
+        if let Some(val) = kv_util::decode_str(Some(v.to_vec()))? {
+            if page.entries.len() == limit {
+                more = true;
+                return Ok(false);
+            }
+            page.entries.push((key.to_vec(), val));
+        }
+        Ok(true)
+    })?;
+    if more {
+        page.cursor = page.entries.last().map(|(k, _)| k.clone());
+    }
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    fn matches(pattern: &str, s: &str) -> bool {
+        glob_match(pattern.as_bytes(), s.as_bytes())
+    }
+
+    #[test]
+    fn literal() {
+        assert!(matches("user:1", "user:1"));
+        assert!(!matches("user:1", "user:12"));
+        assert!(!matches("user:12", "user:1"));
+    }
+
+    #[test]
+    fn star_at_the_start() {
+        assert!(matches("*:1", "user:1"));
+        assert!(matches("*:1", ":1"));
+        assert!(!matches("*:1", "user:12"));
+    }
+
+    #[test]
+    fn star_in_the_middle() {
+        assert!(matches("user:*:name", "user:42:name"));
+        assert!(matches("user:*:name", "user::name"));
+        assert!(matches("a*b", "axxbxxb"));
+        assert!(!matches("user:*:name", "user:42:mail"));
+    }
+
+    #[test]
+    fn star_at_the_end() {
+        assert!(matches("user:*", "user:"));
+        assert!(matches("user:*", "user:42"));
+        assert!(!matches("user:*", "use"));
+    }
+
+    #[test]
+    fn question_mark() {
+        assert!(matches("user:?", "user:1"));
+        assert!(!matches("user:?", "user:"));
+        assert!(!matches("user:?", "user:12"));
+        assert!(matches("?*?", "ab"));
+        assert!(!matches("?*?", "a"));
+    }
+
+    #[test]
+    fn empty_pattern() {
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn empty_string() {
+        assert!(matches("*", ""));
+        assert!(matches("**", ""));
+        assert!(!matches("?", ""));
+        assert!(!matches("a", ""));
+    }
+}
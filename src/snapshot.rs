@@ -0,0 +1,160 @@
+//! named RocksDB snapshots
+//!
+//! a client takes a snapshot once and passes its token along with later
+//! reads, so a read spread over several requests, like the pages of a
+//! scan, sees the database as it was when the snapshot was taken
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocksdb::{BoundColumnFamily, Direction, IteratorMode, SnapshotWithThreadMode};
+
+use crate::error::StorageError;
+use crate::kv_util::{is_root_key, RocksDb, RocksKvUtil};
+use crate::scan::{self, ScanOpts, ScanPage};
+use crate::ttl;
+use crate::txn::{RawEngine, Txn};
+
+/// the longest `create_snapshot` holds a snapshot
+pub const MAX_SNAPSHOT_TTL: Duration = Duration::from_secs(10 * 60);
+/// the most snapshots held at once, past it `create_snapshot` refuses
+pub const MAX_SNAPSHOTS: usize = 64;
+
+struct HeldSnapshot {
+    // borrows from `_db`, so it is declared first to be dropped first,
+    // see `create_snapshot`
+    snapshot: SnapshotWithThreadMode<'static, RocksDb>,
+    _db: Arc<RocksDb>,
+    deadline: u64,
+}
+
+/// the snapshots handed out by a database, shared by all its handles
+#[derive(Default)]
+pub(crate) struct Snapshots {
+    last_token: u64,
+    held: HashMap<u64, Arc<HeldSnapshot>>,
+}
+
+/// reads from one snapshot, so a `Txn` reading several keys sees them
+/// all as of the same point in time
+pub(crate) struct SnapshotReader<'a> {
+    pub(crate) snapshot: &'a SnapshotWithThreadMode<'a, RocksDb>,
+    pub(crate) cf: Arc<BoundColumnFamily<'a>>,
+    // the default column family, for the root keys
+    pub(crate) root: Arc<BoundColumnFamily<'a>>,
+}
+
+impl<'a> RawEngine for SnapshotReader<'a> {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let cf = if is_root_key(key) { &self.root } else { &self.cf };
+        Ok(self.snapshot.get_cf(cf, key)?)
+    }
+
+    fn scan(
+        &mut self,
+        from: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError> {
+        let mode = IteratorMode::From(from, Direction::Forward);
+        for item in self.snapshot.iterator_cf(&self.cf, mode) {
+            let (k, v) = item?;
+            if !f(&k, &v)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_rev(
+        &mut self,
+        from: &[u8],
+        to: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError> {
+        let mode = IteratorMode::From(to, Direction::Reverse);
+        for item in self.snapshot.iterator_cf(&self.cf, mode) {
+            let (k, v) = item?;
+            // the iterator starts on `to` itself when it is there
+            if &k[..] >= to {
+                continue;
+            }
+            if &k[..] < from || !f(&k, &v)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RocksKvUtil {
+    /// take a snapshot of every namespace and return its token
+    ///
+    /// the snapshot keeps the data it sees from being compacted away,
+    /// so it is released after `ttl`, at most `MAX_SNAPSHOT_TTL`, if
+    /// `release_snapshot` didn't first. with `MAX_SNAPSHOTS` held this
+    /// fails with `StorageError::TooManySnapshots`
+    pub fn create_snapshot(&self, ttl: Duration) -> Result<u64, StorageError> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.held.retain(|_, held| !ttl::is_expired(held.deadline));
+        if snapshots.held.len() >= MAX_SNAPSHOTS {
+            return Err(StorageError::TooManySnapshots);
+        }
+        let snapshot = self.db.snapshot();
+        // SAFETY: the snapshot borrows the database. `HeldSnapshot` stores
+        // it next to an `Arc` of that database, and fields drop in the order
+        // they are declared, so the snapshot is released before the `Arc`
+        // can let the database go. the `'static` snapshot never leaves its
+        // `HeldSnapshot`, readers only borrow it while holding the
+        // `Arc<HeldSnapshot>`
+        let snapshot = unsafe {
+            mem::transmute::<
+                SnapshotWithThreadMode<'_, RocksDb>,
+                SnapshotWithThreadMode<'static, RocksDb>,
+            >(snapshot)
+        };
+        let held = HeldSnapshot {
+            snapshot,
+            _db: self.db.clone(),
+            deadline: ttl::deadline(Some(ttl.min(MAX_SNAPSHOT_TTL))),
+        };
+        snapshots.last_token += 1;
+        let token = snapshots.last_token;
+        snapshots.held.insert(token, Arc::new(held));
+        Ok(token)
+    }
+
+    /// release the snapshot `token`, false if it was already gone
+    pub fn release_snapshot(&self, token: u64) -> bool {
+        self.snapshots.lock().unwrap().held.remove(&token).is_some()
+    }
+
+    /// `KvUtil::scan` of the namespace as it was at the snapshot `token`
+    pub fn scan_snapshot(&self, token: u64, opts: &ScanOpts) -> Result<ScanPage, StorageError> {
+        let held = self
+            .snapshots
+            .lock()
+            .unwrap()
+            .held
+            .get(&token)
+            .filter(|held| !ttl::is_expired(held.deadline))
+            .cloned()
+            .ok_or(StorageError::UnknownSnapshot(token))?;
+        let mut reader = SnapshotReader {
+            snapshot: &held.snapshot,
+            cf: self.cf()?,
+            root: self.root_cf()?,
+        };
+        scan::page(&mut Txn::new(&mut reader), opts)
+    }
+
+    /// drop the snapshots past their deadline, for the expiry sweep
+    pub(crate) fn release_expired_snapshots(&self) {
+        self.snapshots
+            .lock()
+            .unwrap()
+            .held
+            .retain(|_, held| !ttl::is_expired(held.deadline));
+    }
+}
@@ -0,0 +1,82 @@
+//! what the RocksDB engine is doing, from its properties and tickers
+
+use rocksdb::statistics::Ticker;
+
+use crate::error::StorageError;
+use crate::kv_util::RocksKvUtil;
+
+/// the state of one namespace, plus the counters of the whole database
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EngineStats {
+    /// keys in the namespace as RocksDB estimates them,
+    /// overwritten and deleted keys may be counted more than once
+    pub estimate_num_keys: u64,
+    /// bytes in the active and the unflushed immutable memtables
+    pub cur_size_all_mem_tables: u64,
+    /// like `cur_size_all_mem_tables` with the flushed ones still pinned
+    pub size_all_mem_tables: u64,
+    /// bytes of the live sst files of each level, level 0 first
+    pub sst_bytes_per_level: Vec<u64>,
+    /// bytes compactions have to rewrite to bring every level under its target
+    pub estimate_pending_compaction_bytes: u64,
+    /// database wide from here on
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+    /// micros writes were stalled for since the database opened
+    pub stall_micros: u64,
+    /// bytes per second writes are held to, 0 when they aren't slowed down
+    pub delayed_write_rate: u64,
+    /// writes wait until compactions catch up
+    pub write_stopped: bool,
+}
+
+impl EngineStats {
+    /// the share of block reads served from the cache,
+    /// `None` before the first one
+    pub fn block_cache_hit_rate(&self) -> Option<f64> {
+        let total = self.block_cache_hits + self.block_cache_misses;
+        if total == 0 {
+            return None;
+        }
+        Some(self.block_cache_hits as f64 / total as f64)
+    }
+}
+
+impl RocksKvUtil {
+    /// the current `EngineStats` of the namespace
+    pub fn stats(&self) -> Result<EngineStats, StorageError> {
+        let cf = self.cf()?;
+        let cf_int = |name: &str| -> Result<u64, StorageError> {
+            Ok(self.db.property_int_value_cf(&cf, name)?.unwrap_or(0))
+        };
+        let db_int = |name: &str| -> Result<u64, StorageError> {
+            Ok(self.db.property_int_value(name)?.unwrap_or(0))
+        };
+
+        let mut sst_bytes_per_level = Vec::new();
+        for file in self.db.live_files()? {
+            if file.column_family_name != *self.ns {
+                continue;
+            }
+            let level = file.level.max(0) as usize;
+            if sst_bytes_per_level.len() <= level {
+                sst_bytes_per_level.resize(level + 1, 0);
+            }
+            sst_bytes_per_level[level] += file.size as u64;
+        }
+
+        Ok(EngineStats {
+            estimate_num_keys: cf_int("rocksdb.estimate-num-keys")?,
+            cur_size_all_mem_tables: cf_int("rocksdb.cur-size-all-mem-tables")?,
+            size_all_mem_tables: cf_int("rocksdb.size-all-mem-tables")?,
+            sst_bytes_per_level,
+            estimate_pending_compaction_bytes: cf_int("rocksdb.estimate-pending-compaction-bytes")?,
+            block_cache_hits: self.db_opts.get_ticker_count(Ticker::BlockCacheHit),
+            block_cache_misses: self.db_opts.get_ticker_count(Ticker::BlockCacheMiss),
+            stall_micros: self.db_opts.get_ticker_count(Ticker::StallMicros),
+            delayed_write_rate: db_int("rocksdb.actual-delayed-write-rate")?,
+            write_stopped: db_int("rocksdb.is-write-stopped")? != 0,
+        })
+    }
+}
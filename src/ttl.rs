@@ -0,0 +1,99 @@
+//! key expiration
+//!
+//! expiring values carry their deadline (unix millis, 0 for never) in the
+//! record itself, so reads can hide them without another lookup.
+//! every deadline also gets an entry in the expiry index
+//!
+//! `x | deadline | type tag | key` -> ()
+//!
+//! which a background coroutine of each engine walks in deadline order
+//! to reclaim what expired
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::StorageError;
+use crate::kv_util::{self, STR_TAG};
+use crate::txn::Txn;
+use crate::zset::{self, ZSET_TAG};
+
+const EXPIRY_TAG: u8 = b'x';
+
+/// how often the engines look for expired keys
+pub(crate) const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the most index entries one sweep transaction looks at
+pub(crate) const SWEEP_BATCH: usize = 1024;
+
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// the deadline for a value written now, 0 for no `ttl`
+pub(crate) fn deadline(ttl: Option<Duration>) -> u64 {
+    match ttl {
+        Some(ttl) => now_ms().saturating_add(ttl.as_millis() as u64).max(1),
+        None => 0,
+    }
+}
+
+pub(crate) fn is_expired(deadline: u64) -> bool {
+    is_expired_at(deadline, now_ms())
+}
+
+/// whether `deadline` had passed at the unix millis `at`
+pub(crate) fn is_expired_at(deadline: u64, at: u64) -> bool {
+    deadline != 0 && deadline <= at
+}
+
+fn index_key(deadline: u64, tag: u8, key: &[u8]) -> Vec<u8> {
+    let mut k = Vec::with_capacity(key.len() + 10);
+    k.push(EXPIRY_TAG);
+    k.extend_from_slice(&deadline.to_be_bytes());
+    k.push(tag);
+    k.extend_from_slice(key);
+    k
+}
+
+/// remember that the `tag` typed `key` expires at `deadline`
+///
+/// stale entries, left behind when a key is rewritten, are fine:
+/// the sweep checks the key's current deadline before dropping it
+pub(crate) fn track(txn: &mut Txn, tag: u8, key: &[u8], deadline: u64) {
+    if deadline != 0 {
+        txn.put(&index_key(deadline, tag, key), b"");
+    }
+}
+
+/// reclaim the keys that expired by now, looking at no more than
+/// `SWEEP_BATCH` index entries, and return how many were looked at
+pub(crate) fn sweep(txn: &mut Txn) -> Result<usize, StorageError> {
+    let now = now_ms();
+    let mut due = Vec::new();
+    txn.scan(&[EXPIRY_TAG], &mut |k, _| {
+        if k.len() < 10 || k[0] != EXPIRY_TAG || due.len() >= SWEEP_BATCH {
+            return Ok(false);
+        }
+        let mut deadline = [0u8; 8];
+        deadline.copy_from_slice(&k[1..9]);
+        let deadline = u64::from_be_bytes(deadline);
+        if deadline > now {
+            return Ok(false);
+        }
+        due.push((k.to_vec(), deadline));
+        Ok(true)
+    })?;
+
+    for (index, deadline) in due.iter() {
+        let (tag, key) = (index[9], &index[10..]);
+        match tag {
+            STR_TAG => kv_util::expire_str(txn, key, *deadline)?,
+            ZSET_TAG => zset::expire(txn, key, *deadline)?,
+            _ => {}
+        }
+        txn.delete(index);
+    }
+    Ok(due.len())
+}
@@ -0,0 +1,415 @@
+//! read-modify-write transactions the composite data types are built on
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::error::StorageError;
+
+/// the reads an engine has to offer a `Txn`
+pub(crate) trait RawEngine {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+    /// visit the entries from `from` on in key order until `f` returns false
+    fn scan(
+        &mut self,
+        from: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError>;
+
+    /// visit the entries from `from` up to `to`, excluded, last first,
+    /// until `f` returns false
+    ///
+    /// the default reads them forward and visits them from a buffer,
+    /// engines with reverse iterators stop as soon as `f` does
+    fn scan_rev(
+        &mut self,
+        from: &[u8],
+        to: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError> {
+        let mut entries = Vec::new();
+        self.scan(from, &mut |k, v| {
+            if k >= to {
+                return Ok(false);
+            }
+            entries.push((k.to_vec(), v.to_vec()));
+            Ok(true)
+        })?;
+        for (k, v) in entries.iter().rev() {
+            if !f(k, v)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// pending writes over an engine
+///
+/// writes are buffered until the engine commits them all in one batch,
+/// reads see the buffered writes on top of what the engine holds
+pub(crate) struct Txn<'a> {
+    engine: &'a mut dyn RawEngine,
+    // `None` marks a delete
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // `from .. to` ranges deleted, under the entries in `pending`
+    ranges: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// what a `Txn` leaves for the engine to commit, the range deletes go
+/// first so the puts and deletes in `keys` land on top of them
+pub(crate) struct Writes {
+    pub(crate) ranges: Vec<(Vec<u8>, Vec<u8>)>,
+    pub(crate) keys: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+fn in_ranges(ranges: &[(Vec<u8>, Vec<u8>)], key: &[u8]) -> bool {
+    ranges
+        .iter()
+        .any(|(from, to)| key >= from.as_slice() && key < to.as_slice())
+}
+
+// hand `f` what the engine scan `engine_scan` visits with the `pending`
+// writes laid over it, both in the order `before` tells
+fn overlay<'p>(
+    engine_scan: impl FnOnce(
+        &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError>,
+    pending: impl Iterator<Item = (&'p Vec<u8>, &'p Option<Vec<u8>>)>,
+    ranges: &[(Vec<u8>, Vec<u8>)],
+    before: fn(&[u8], &[u8]) -> bool,
+    f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+) -> Result<(), StorageError> {
+    let mut pending = pending.peekable();
+    let mut stopped = false;
+    engine_scan(&mut |k, v| {
+        // pending entries coming before `k` go first
+        while let Some(&(pk, pv)) = pending.peek() {
+            if !before(pk, k) {
+                break;
+            }
+            if let Some(pv) = pv {
+                if !f(pk, pv)? {
+                    stopped = true;
+                    return Ok(false);
+                }
+            }
+            pending.next();
+        }
+        let go_on = match pending.peek() {
+            Some(&(pk, pv)) if pk.as_slice() == k => {
+                let go_on = match pv {
+                    Some(pv) => f(k, pv)?,
+                    None => true,
+                };
+                pending.next();
+                go_on
+            }
+            _ if in_ranges(ranges, k) => true,
+            _ => f(k, v)?,
+        };
+        stopped = !go_on;
+        Ok(go_on)
+    })?;
+    if !stopped {
+        for (pk, pv) in pending {
+            if let Some(pv) = pv {
+                if !f(pk, pv)? {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<'a> Txn<'a> {
+    pub(crate) fn new(engine: &'a mut dyn RawEngine) -> Self {
+        Txn {
+            engine,
+            pending: BTreeMap::new(),
+            ranges: Vec::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        match self.pending.get(key) {
+            Some(v) => Ok(v.clone()),
+            None if in_ranges(&self.ranges, key) => Ok(None),
+            None => self.engine.get(key),
+        }
+    }
+
+    /// visit the entries from `from` on in key order until `f` returns false
+    pub(crate) fn scan(
+        &mut self,
+        from: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError> {
+        let engine = &mut *self.engine;
+        overlay(
+            |visit| engine.scan(from, visit),
+            self.pending.range::<[u8], _>((Bound::Included(from), Bound::Unbounded)),
+            &self.ranges,
+            |a, b| a < b,
+            f,
+        )
+    }
+
+    /// visit the entries from `from` up to `to`, excluded, last first,
+    /// until `f` returns false
+    pub(crate) fn scan_rev(
+        &mut self,
+        from: &[u8],
+        to: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError> {
+        if from >= to {
+            return Ok(());
+        }
+        let engine = &mut *self.engine;
+        overlay(
+            |visit| engine.scan_rev(from, to, visit),
+            self.pending
+                .range::<[u8], _>((Bound::Included(from), Bound::Excluded(to)))
+                .rev(),
+            &self.ranges,
+            |a, b| a > b,
+            f,
+        )
+    }
+
+    pub(crate) fn put(&mut self, key: &[u8], val: &[u8]) {
+        self.pending.insert(key.to_vec(), Some(val.to_vec()));
+    }
+
+    pub(crate) fn delete(&mut self, key: &[u8]) {
+        self.pending.insert(key.to_vec(), None);
+    }
+
+    /// delete every key from `from` up to `to`, excluded
+    ///
+    /// the engine drops the range in one go rather than key by key
+    pub(crate) fn delete_range(&mut self, from: &[u8], to: &[u8]) {
+        if from >= to {
+            return;
+        }
+        let mut tail = self.pending.split_off(from);
+        let mut rest = tail.split_off(to);
+        self.pending.append(&mut rest);
+        self.ranges.push((from.to_vec(), to.to_vec()));
+    }
+
+    /// the buffered writes for the engine to commit
+    pub(crate) fn into_writes(self) -> Writes {
+        Writes {
+            ranges: self.ranges,
+            keys: self.pending,
+        }
+    }
+}
+
+/// an engine in memory for the data type tests
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MemEngine(pub(crate) BTreeMap<Vec<u8>, Vec<u8>>);
+
+#[cfg(test)]
+impl MemEngine {
+    /// run `f` in a `Txn` and commit what it wrote, as the engines do
+    pub(crate) fn update<R>(
+        &mut self,
+        f: impl FnOnce(&mut Txn) -> Result<R, StorageError>,
+    ) -> Result<R, StorageError> {
+        let mut txn = Txn::new(self);
+        let ret = f(&mut txn)?;
+        let writes = txn.into_writes();
+        for (from, to) in writes.ranges {
+            self.0.retain(|k, _| k < &from || k >= &to);
+        }
+        for (k, v) in writes.keys {
+            match v {
+                Some(v) => self.0.insert(k, v),
+                None => self.0.remove(&k),
+            };
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+impl RawEngine for MemEngine {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.0.get(key).cloned())
+    }
+
+    fn scan(
+        &mut self,
+        from: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool, StorageError>,
+    ) -> Result<(), StorageError> {
+        for (k, v) in self.0.range::<[u8], _>((Bound::Included(from), Bound::Unbounded)) {
+            if !f(k, v)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(keys: &[&str]) -> MemEngine {
+        let mut engine = MemEngine::default();
+        for k in keys {
+            engine.0.insert(k.as_bytes().to_vec(), b"engine".to_vec());
+        }
+        engine
+    }
+
+    // the `key=value` pairs a scan from `from` visits, stopping after `limit`
+    fn scan(txn: &mut Txn, from: &str, limit: usize) -> Vec<String> {
+        let mut seen = Vec::new();
+        txn.scan(from.as_bytes(), &mut |k, v| {
+            seen.push(format!(
+                "{}={}",
+                String::from_utf8_lossy(k),
+                String::from_utf8_lossy(v)
+            ));
+            Ok(seen.len() < limit)
+        })
+        .unwrap();
+        seen
+    }
+
+    #[test]
+    fn pending_writes_interleave_with_the_engine() {
+        let mut engine = engine(&["b", "d", "f"]);
+        let mut txn = Txn::new(&mut engine);
+        txn.put(b"a", b"txn");
+        txn.put(b"c", b"txn");
+        txn.put(b"d", b"txn");
+        txn.put(b"e", b"txn");
+        assert_eq!(
+            scan(&mut txn, "", usize::MAX),
+            ["a=txn", "b=engine", "c=txn", "d=txn", "e=txn", "f=engine"]
+        );
+    }
+
+    #[test]
+    fn pending_deletes_hide_engine_entries() {
+        let mut engine = engine(&["a", "b", "c"]);
+        let mut txn = Txn::new(&mut engine);
+        txn.delete(b"b");
+        txn.delete(b"x");
+        assert_eq!(scan(&mut txn, "", usize::MAX), ["a=engine", "c=engine"]);
+        assert_eq!(txn.get(b"b").unwrap(), None);
+        assert_eq!(txn.get(b"c").unwrap(), Some(b"engine".to_vec()));
+    }
+
+    #[test]
+    fn pending_tail_after_the_engine_ends() {
+        let mut engine = engine(&["a"]);
+        let mut txn = Txn::new(&mut engine);
+        txn.put(b"y", b"txn");
+        txn.delete(b"z");
+        txn.put(b"zz", b"txn");
+        assert_eq!(scan(&mut txn, "", usize::MAX), ["a=engine", "y=txn", "zz=txn"]);
+    }
+
+    #[test]
+    fn scan_starts_at_from() {
+        let mut engine = engine(&["a", "c"]);
+        let mut txn = Txn::new(&mut engine);
+        txn.put(b"b", b"txn");
+        txn.put(b"d", b"txn");
+        assert_eq!(scan(&mut txn, "bb", usize::MAX), ["c=engine", "d=txn"]);
+    }
+
+    #[test]
+    fn early_stop() {
+        let mut engine = engine(&["b", "d"]);
+        let mut txn = Txn::new(&mut engine);
+        txn.put(b"a", b"txn");
+        txn.put(b"c", b"txn");
+        txn.put(b"e", b"txn");
+        // on a pending entry before an engine one
+        assert_eq!(scan(&mut txn, "", 1), ["a=txn"]);
+        // on an engine entry
+        assert_eq!(scan(&mut txn, "", 2), ["a=txn", "b=engine"]);
+        // with pending entries left over after the engine ends
+        assert_eq!(scan(&mut txn, "", 4), ["a=txn", "b=engine", "c=txn", "d=engine"]);
+        // in the leftover tail
+        txn.put(b"f", b"txn");
+        assert_eq!(
+            scan(&mut txn, "", 5),
+            ["a=txn", "b=engine", "c=txn", "d=engine", "e=txn"]
+        );
+    }
+
+    #[test]
+    fn early_stop_on_an_overwritten_entry() {
+        let mut engine = engine(&["a", "b"]);
+        let mut txn = Txn::new(&mut engine);
+        txn.put(b"a", b"txn");
+        assert_eq!(scan(&mut txn, "", 1), ["a=txn"]);
+    }
+
+    // the `key=value` pairs a reverse scan of `from .. to` visits
+    fn scan_rev(txn: &mut Txn, from: &str, to: &str, limit: usize) -> Vec<String> {
+        let mut seen = Vec::new();
+        txn.scan_rev(from.as_bytes(), to.as_bytes(), &mut |k, v| {
+            seen.push(format!(
+                "{}={}",
+                String::from_utf8_lossy(k),
+                String::from_utf8_lossy(v)
+            ));
+            Ok(seen.len() < limit)
+        })
+        .unwrap();
+        seen
+    }
+
+    #[test]
+    fn reverse_scan_interleaves_and_stays_in_bounds() {
+        let mut engine = engine(&["a", "b", "d", "f", "g"]);
+        let mut txn = Txn::new(&mut engine);
+        txn.put(b"c", b"txn");
+        txn.put(b"d", b"txn");
+        txn.delete(b"b");
+        txn.put(b"e", b"txn");
+        txn.put(b"g", b"txn");
+        assert_eq!(
+            scan_rev(&mut txn, "b", "g", usize::MAX),
+            ["f=engine", "e=txn", "d=txn", "c=txn"]
+        );
+        assert_eq!(scan_rev(&mut txn, "b", "g", 2), ["f=engine", "e=txn"]);
+        assert_eq!(scan_rev(&mut txn, "", "zz", 1), ["g=txn"]);
+        assert!(scan_rev(&mut txn, "c", "c", usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn reverse_scan_skips_deleted_ranges() {
+        let mut engine = engine(&["a", "b", "c"]);
+        let mut txn = Txn::new(&mut engine);
+        txn.delete_range(b"b", b"c");
+        assert_eq!(scan_rev(&mut txn, "", "z", usize::MAX), ["c=engine", "a=engine"]);
+    }
+
+    #[test]
+    fn delete_range_hides_engine_and_pending_entries() {
+        let mut engine = engine(&["a", "b", "c", "d"]);
+        let mut txn = Txn::new(&mut engine);
+        txn.put(b"bb", b"txn");
+        txn.delete_range(b"b", b"d");
+        txn.put(b"c", b"txn");
+        assert_eq!(scan(&mut txn, "", usize::MAX), ["a=engine", "c=txn", "d=engine"]);
+        assert_eq!(txn.get(b"b").unwrap(), None);
+        assert_eq!(txn.get(b"bb").unwrap(), None);
+        assert_eq!(txn.get(b"d").unwrap(), Some(b"engine".to_vec()));
+        let writes = txn.into_writes();
+        assert_eq!(writes.ranges, [(b"b".to_vec(), b"d".to_vec())]);
+        assert_eq!(writes.keys.len(), 1);
+    }
+}
@@ -0,0 +1,549 @@
+//! sorted sets on top of the plain key value engines
+//!
+//! every set keeps two indexes side by side
+//!
+//! `z | key len | key | 'm' | member` -> score, to find a member's score
+//! `z | key len | key | 's' | score | member` -> (), ordered by score
+//!
+//! plus `z | key len | key | 'n'` -> member count and deadline of the set,
+//! so `zcard` needn't scan and the whole set can expire at once
+//!
+//! scores are stored big endian so the engine's byte order is score order
+
+use std::ops::Bound;
+use std::time::Duration;
+
+use crate::error::StorageError;
+use crate::keys;
+use crate::ttl;
+use crate::txn::Txn;
+
+pub(crate) const ZSET_TAG: u8 = b'z';
+const MEMBER_TAG: u8 = b'm';
+const META_TAG: u8 = b'n';
+const SCORE_TAG: u8 = b's';
+
+fn set_prefix(key: &[u8], index: u8) -> Vec<u8> {
+    keys::composite_prefix(ZSET_TAG, key, index)
+}
+
+fn member_key(key: &[u8], member: &[u8]) -> Vec<u8> {
+    let mut k = set_prefix(key, MEMBER_TAG);
+    k.extend_from_slice(member);
+    k
+}
+
+fn score_key(key: &[u8], score: u32, member: &[u8]) -> Vec<u8> {
+    let mut k = set_prefix(key, SCORE_TAG);
+    k.extend_from_slice(&score.to_be_bytes());
+    k.extend_from_slice(member);
+    k
+}
+
+fn decode_score(v: &[u8]) -> Result<u32, StorageError> {
+    if v.len() < 4 {
+        return Err(StorageError::Corrupted(format!(
+            "sorted set score of {} bytes",
+            v.len()
+        )));
+    }
+    let mut score = [0u8; 4];
+    score.copy_from_slice(&v[..4]);
+    Ok(u32::from_be_bytes(score))
+}
+
+// the `'n'` record, a missing one is an empty set without deadline
+#[derive(Default)]
+struct Meta {
+    count: u64,
+    deadline: u64,
+}
+
+fn load_meta(txn: &mut Txn, key: &[u8]) -> Result<Meta, StorageError> {
+    match txn.get(&meta_key(key))? {
+        Some(v) => decode_meta(&v),
+        None => Ok(Meta::default()),
+    }
+}
+
+fn decode_meta(v: &[u8]) -> Result<Meta, StorageError> {
+    if v.len() != 16 {
+        return Err(StorageError::Corrupted(format!(
+            "sorted set meta of {} bytes",
+            v.len()
+        )));
+    }
+    let mut count = [0u8; 8];
+    let mut deadline = [0u8; 8];
+    count.copy_from_slice(&v[..8]);
+    deadline.copy_from_slice(&v[8..]);
+    Ok(Meta {
+        count: u64::from_be_bytes(count),
+        deadline: u64::from_be_bytes(deadline),
+    })
+}
+
+fn save_meta(txn: &mut Txn, key: &[u8], meta: &Meta) {
+    if meta.count == 0 {
+        txn.delete(&meta_key(key));
+    } else {
+        let mut v = [0u8; 16];
+        v[..8].copy_from_slice(&meta.count.to_be_bytes());
+        v[8..].copy_from_slice(&meta.deadline.to_be_bytes());
+        txn.put(&meta_key(key), &v);
+    }
+}
+
+/// the engine key of the set's `'n'` record
+pub(crate) fn meta_key(key: &[u8]) -> Vec<u8> {
+    set_prefix(key, META_TAG)
+}
+
+/// the deadline kept in a `'n'` record
+pub(crate) fn meta_deadline(record: &[u8]) -> Result<u64, StorageError> {
+    Ok(decode_meta(record)?.deadline)
+}
+
+/// the set key, member and score of a `'m'` record,
+/// `None` for the set's other records
+pub(crate) fn decode_member<'a>(
+    k: &'a [u8],
+    v: &[u8],
+) -> Result<Option<(&'a [u8], &'a [u8], u32)>, StorageError> {
+    match keys::decode_composite(ZSET_TAG, k)? {
+        Some((key, MEMBER_TAG, member)) => Ok(Some((key, member, decode_score(v)?))),
+        _ => Ok(None),
+    }
+}
+
+// drop every record of the set
+fn purge(txn: &mut Txn, key: &[u8]) -> Result<(), StorageError> {
+    let prefix = keys::key_prefix(ZSET_TAG, key);
+    let mut records = Vec::new();
+    txn.scan(&prefix, &mut |k, _| {
+        if !k.starts_with(&prefix) {
+            return Ok(false);
+        }
+        records.push(k.to_vec());
+        Ok(true)
+    })?;
+    for k in records.iter() {
+        txn.delete(k);
+    }
+    Ok(())
+}
+
+// the meta of a set about to be written, an expired set is purged first
+fn live_meta(txn: &mut Txn, key: &[u8]) -> Result<Meta, StorageError> {
+    let meta = load_meta(txn, key)?;
+    if ttl::is_expired(meta.deadline) {
+        purge(txn, key)?;
+        return Ok(Meta::default());
+    }
+    Ok(meta)
+}
+
+// expired sets read as empty until the sweep gets to them
+fn is_live(txn: &mut Txn, key: &[u8]) -> Result<bool, StorageError> {
+    Ok(!ttl::is_expired(load_meta(txn, key)?.deadline))
+}
+
+/// purge the set if it is still due at `deadline`, for the expiry sweep
+pub(crate) fn expire(txn: &mut Txn, key: &[u8], deadline: u64) -> Result<(), StorageError> {
+    let meta = load_meta(txn, key)?;
+    if meta.deadline == deadline && ttl::is_expired(deadline) {
+        purge(txn, key)?;
+    }
+    Ok(())
+}
+
+/// the number of members in the set, 0 for a missing set
+pub(crate) fn card(txn: &mut Txn, key: &[u8]) -> Result<u64, StorageError> {
+    let meta = load_meta(txn, key)?;
+    if ttl::is_expired(meta.deadline) {
+        return Ok(0);
+    }
+    Ok(meta.count)
+}
+
+// the score of `member` without the expiry check
+fn raw_score(txn: &mut Txn, key: &[u8], member: &[u8]) -> Result<Option<u32>, StorageError> {
+    match txn.get(&member_key(key, member))? {
+        Some(v) => Ok(Some(decode_score(&v)?)),
+        None => Ok(None),
+    }
+}
+
+/// the score of `member`, if it is in the set
+pub(crate) fn score(
+    txn: &mut Txn,
+    key: &[u8],
+    member: &[u8],
+) -> Result<Option<u32>, StorageError> {
+    if !is_live(txn, key)? {
+        return Ok(None);
+    }
+    raw_score(txn, key, member)
+}
+
+/// add `member` or move it to a new score
+///
+/// with a `ttl` the whole set expires that long from now,
+/// without one it keeps the deadline it had
+pub(crate) fn add(
+    txn: &mut Txn,
+    key: &[u8],
+    member: &[u8],
+    new_score: u32,
+    ttl: Option<Duration>,
+) -> Result<(), StorageError> {
+    let mut meta = live_meta(txn, key)?;
+    if ttl.is_some() {
+        meta.deadline = ttl::deadline(ttl);
+        ttl::track(txn, ZSET_TAG, key, meta.deadline);
+    }
+    match raw_score(txn, key, member)? {
+        Some(old) if old == new_score => {}
+        Some(old) => {
+            txn.delete(&score_key(key, old, member));
+            txn.put(&member_key(key, member), &new_score.to_be_bytes());
+            txn.put(&score_key(key, new_score, member), b"");
+        }
+        None => {
+            meta.count += 1;
+            txn.put(&member_key(key, member), &new_score.to_be_bytes());
+            txn.put(&score_key(key, new_score, member), b"");
+        }
+    }
+    save_meta(txn, key, &meta);
+    Ok(())
+}
+
+/// drop `member` from both indexes, a missing member is not an error
+pub(crate) fn remove(txn: &mut Txn, key: &[u8], member: &[u8]) -> Result<(), StorageError> {
+    let mut meta = live_meta(txn, key)?;
+    if let Some(old) = raw_score(txn, key, member)? {
+        txn.delete(&score_key(key, old, member));
+        txn.delete(&member_key(key, member));
+        meta.count -= 1;
+        save_meta(txn, key, &meta);
+    }
+    Ok(())
+}
+
+/// add `delta` to the score of `member`, a missing member starts at 0
+///
+/// the new score saturates at the ends of the `u32` range
+pub(crate) fn incr_by(
+    txn: &mut Txn,
+    key: &[u8],
+    member: &[u8],
+    delta: i64,
+) -> Result<u32, StorageError> {
+    let old = score(txn, key, member)?.unwrap_or(0);
+    let new_score = (old as i64).saturating_add(delta).clamp(0, u32::MAX as i64) as u32;
+    add(txn, key, member, new_score, None)?;
+    Ok(new_score)
+}
+
+/// the 0 based position of `member` in score order
+pub(crate) fn rank(
+    txn: &mut Txn,
+    key: &[u8],
+    member: &[u8],
+) -> Result<Option<u64>, StorageError> {
+    let target = match score(txn, key, member)? {
+        Some(s) => score_key(key, s, member),
+        None => return Ok(None),
+    };
+    let prefix = set_prefix(key, SCORE_TAG);
+    let mut rank = 0;
+    let mut found = false;
+    txn.scan(&prefix, &mut |k, _| {
+        if !k.starts_with(&prefix) {
+            return Ok(false);
+        }
+        if k == &target[..] {
+            found = true;
+            return Ok(false);
+        }
+        rank += 1;
+        Ok(true)
+    })?;
+    // only a read seeing the set change between the two lookups
+    // misses the member, there is no rank to give then
+    Ok(Some(rank).filter(|_| found))
+}
+
+/// which members `KvUtil::zrange` returns and in what order
+///
+/// the default takes every member, lowest score first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZRange {
+    pub min: Bound<u32>,
+    pub max: Bound<u32>,
+    /// members skipped from the start of the result
+    pub offset: usize,
+    /// at most this many members after `offset`
+    pub limit: Option<usize>,
+    /// highest score first
+    pub reverse: bool,
+}
+
+impl Default for ZRange {
+    fn default() -> Self {
+        ZRange {
+            min: Bound::Unbounded,
+            max: Bound::Unbounded,
+            offset: 0,
+            limit: None,
+            reverse: false,
+        }
+    }
+}
+
+impl ZRange {
+    // the first score to look at, `None` when nothing can match
+    fn first_score(&self) -> Option<u32> {
+        match self.min {
+            Bound::Included(min) => Some(min),
+            Bound::Excluded(min) => min.checked_add(1),
+            Bound::Unbounded => Some(0),
+        }
+    }
+
+    // the first score past `max`, `None` when that is past `u32::MAX`
+    fn end_score(&self) -> Option<u32> {
+        match self.max {
+            Bound::Included(max) => max.checked_add(1),
+            Bound::Excluded(max) => Some(max),
+            Bound::Unbounded => None,
+        }
+    }
+
+    fn below_max(&self, score: u32) -> bool {
+        match self.max {
+            Bound::Included(max) => score <= max,
+            Bound::Excluded(max) => score < max,
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+/// the `(member, score)` pairs picked by `range`, by score then member,
+/// or the other way round for `reverse`
+pub(crate) fn range(
+    txn: &mut Txn,
+    key: &[u8],
+    range: &ZRange,
+) -> Result<Vec<(Vec<u8>, u32)>, StorageError> {
+    let min = match range.first_score() {
+        Some(min) if is_live(txn, key)? => min,
+        _ => return Ok(Vec::new()),
+    };
+    let prefix = set_prefix(key, SCORE_TAG);
+    let mut from = prefix.clone();
+    from.extend_from_slice(&min.to_be_bytes());
+
+    // the scan stops as soon as the page is full, either way round
+    let wanted = match range.limit {
+        Some(limit) => range.offset.saturating_add(limit),
+        None => usize::MAX,
+    };
+    let mut members = Vec::new();
+    if range.reverse {
+        // from the first score key past `max`, or the end of the index
+        let to = match range.end_score() {
+            Some(end) => {
+                let mut to = prefix.clone();
+                to.extend_from_slice(&end.to_be_bytes());
+                to
+            }
+            None => set_prefix(key, SCORE_TAG + 1),
+        };
+        txn.scan_rev(&from, &to, &mut |k, _| {
+            if members.len() >= wanted {
+                return Ok(false);
+            }
+            let entry = &k[prefix.len()..];
+            let score = decode_score(entry)?;
+            members.push((entry[4..].to_vec(), score));
+            Ok(true)
+        })?;
+    } else {
+        txn.scan(&from, &mut |k, _| {
+            if !k.starts_with(&prefix) || members.len() >= wanted {
+                return Ok(false);
+            }
+            let entry = &k[prefix.len()..];
+            let score = decode_score(entry)?;
+            if !range.below_max(score) {
+                return Ok(false);
+            }
+            members.push((entry[4..].to_vec(), score));
+            Ok(true)
+        })?;
+    }
+    Ok(members.into_iter().skip(range.offset).collect())
+}
+
+/// how many members have a score within the bounds of `range`,
+/// its offset, limit and order don't matter here
+pub(crate) fn count(txn: &mut Txn, key: &[u8], range: &ZRange) -> Result<u64, StorageError> {
+    let min = match range.first_score() {
+        Some(min) if is_live(txn, key)? => min,
+        _ => return Ok(0),
+    };
+    let prefix = set_prefix(key, SCORE_TAG);
+    let mut from = prefix.clone();
+    from.extend_from_slice(&min.to_be_bytes());
+
+    let mut count = 0;
+    txn.scan(&from, &mut |k, _| {
+        if !k.starts_with(&prefix) || !range.below_max(decode_score(&k[prefix.len()..])?) {
+            return Ok(false);
+        }
+        count += 1;
+        Ok(true)
+    })?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::txn::MemEngine;
+
+    fn set(members: &[(&str, u32)]) -> MemEngine {
+        let mut engine = MemEngine::default();
+        engine
+            .update(|txn| {
+                for (member, score) in members {
+                    add(txn, b"key", member.as_bytes(), *score, None)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        engine
+    }
+
+    #[test]
+    fn moving_a_member_keeps_one_score_record() {
+        let mut engine = set(&[("a", 1), ("b", 2)]);
+        engine
+            .update(|txn| add(txn, b"key", b"a", 3, None))
+            .unwrap();
+        // two member records, two score records and the meta
+        assert_eq!(engine.0.len(), 5);
+        let (card, rank) = engine
+            .update(|txn| Ok((card(txn, b"key")?, rank(txn, b"key", b"a")?)))
+            .unwrap();
+        assert_eq!((card, rank), (2, Some(1)));
+    }
+
+    #[test]
+    fn removing_the_last_member_drops_the_set() {
+        let mut engine = set(&[("a", 1)]);
+        engine
+            .update(|txn| {
+                remove(txn, b"key", b"missing")?;
+                remove(txn, b"key", b"a")
+            })
+            .unwrap();
+        assert!(engine.0.is_empty());
+    }
+
+    fn range_of(engine: &mut MemEngine, range: ZRange) -> Vec<(String, u32)> {
+        engine
+            .update(|txn| super::range(txn, b"key", &range))
+            .unwrap()
+            .into_iter()
+            .map(|(m, s)| (String::from_utf8(m).unwrap(), s))
+            .collect()
+    }
+
+    fn members(picked: &[(String, u32)]) -> Vec<&str> {
+        picked.iter().map(|(m, _)| m.as_str()).collect()
+    }
+
+    fn abcd() -> MemEngine {
+        set(&[("a", 0), ("b", 5), ("c", 5), ("d", u32::MAX)])
+    }
+
+    #[test]
+    fn range_honours_exclusive_bounds() {
+        let mut engine = abcd();
+        let range = ZRange {
+            min: Bound::Excluded(0),
+            max: Bound::Excluded(u32::MAX),
+            ..ZRange::default()
+        };
+        assert_eq!(members(&range_of(&mut engine, range)), ["b", "c"]);
+        let reverse = ZRange { reverse: true, ..range };
+        assert_eq!(members(&range_of(&mut engine, reverse)), ["c", "b"]);
+        let between = ZRange {
+            min: Bound::Excluded(4),
+            max: Bound::Excluded(5),
+            ..ZRange::default()
+        };
+        assert!(range_of(&mut engine, between).is_empty());
+    }
+
+    #[test]
+    fn range_reaches_the_top_score() {
+        let mut engine = abcd();
+        let top = ZRange {
+            min: Bound::Included(u32::MAX),
+            max: Bound::Included(u32::MAX),
+            ..ZRange::default()
+        };
+        assert_eq!(range_of(&mut engine, top), [("d".to_owned(), u32::MAX)]);
+        let reverse = ZRange { reverse: true, ..top };
+        assert_eq!(range_of(&mut engine, reverse), [("d".to_owned(), u32::MAX)]);
+        let past_top = ZRange {
+            min: Bound::Excluded(u32::MAX),
+            ..ZRange::default()
+        };
+        assert!(range_of(&mut engine, past_top).is_empty());
+        let reverse = ZRange { reverse: true, ..past_top };
+        assert!(range_of(&mut engine, reverse).is_empty());
+        assert_eq!(engine.update(|txn| count(txn, b"key", &top)).unwrap(), 1);
+    }
+
+    #[test]
+    fn reverse_range_pages_from_the_top() {
+        let mut engine = abcd();
+        let page = |offset, limit| ZRange {
+            offset,
+            limit,
+            reverse: true,
+            ..ZRange::default()
+        };
+        assert_eq!(members(&range_of(&mut engine, page(0, None))), ["d", "c", "b", "a"]);
+        assert_eq!(members(&range_of(&mut engine, page(1, Some(2)))), ["c", "b"]);
+        assert_eq!(members(&range_of(&mut engine, page(3, Some(5)))), ["a"]);
+        assert!(range_of(&mut engine, page(4, None)).is_empty());
+        assert!(range_of(&mut engine, page(0, Some(0))).is_empty());
+        let below = ZRange {
+            max: Bound::Excluded(u32::MAX),
+            ..page(1, Some(1))
+        };
+        assert_eq!(members(&range_of(&mut engine, below)), ["b"]);
+    }
+
+    #[test]
+    fn reverse_range_stays_in_its_own_set() {
+        let mut engine = abcd();
+        engine
+            .update(|txn| {
+                add(txn, b"kex", b"before", 7, None)?;
+                add(txn, b"kez", b"after", 7, None)
+            })
+            .unwrap();
+        let all = ZRange {
+            reverse: true,
+            ..ZRange::default()
+        };
+        assert_eq!(members(&range_of(&mut engine, all)), ["d", "c", "b", "a"]);
+    }
+}